@@ -0,0 +1,9 @@
+fn main() {
+    let a = 1;
+    fun_name(a);
+}
+
+fn fun_name(a: i32) {
+    let b = a + 2;
+    println!("{}", b);
+}
@@ -0,0 +1,12 @@
+fn main() {
+    let a = fun_name();
+    // rem-extract:start second
+    let b = a * 3;
+    // rem-extract:end
+    println!("{}", b);
+}
+
+fn fun_name() -> i32 {
+    let a = 1 + 2;
+    a
+}
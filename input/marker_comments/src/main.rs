@@ -0,0 +1,7 @@
+fn main() {
+    let a = 1;
+    // rem-extract:start
+    let b = a + 2;
+    println!("{}", b);
+    // rem-extract:end
+}
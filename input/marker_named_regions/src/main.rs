@@ -0,0 +1,9 @@
+fn main() {
+    // rem-extract:start first
+    let a = 1 + 2;
+    // rem-extract:end
+    // rem-extract:start second
+    let b = a * 3;
+    // rem-extract:end
+    println!("{}", b);
+}
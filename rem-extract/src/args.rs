@@ -1,7 +1,11 @@
-use clap::{ArgAction, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
+use ra_ap_syntax::Edition;
+
+use rem_extract::config::{BuildScripts, LoadOptions, ProcMacros};
+use rem_extract::selection::{ColumnUnit, StmtTarget};
 use crate::messages::{about::ABOUT, author::AUTHOR, version::VERSION};
 
 #[derive(Parser)]
@@ -19,20 +23,92 @@ pub struct EXTRACTArgs {
 pub enum EXTRACTCommands {
     // Run the extraction process with specific arguments
     Extract {
-        #[arg(help = "The path to the file to refactor")]
+        #[arg(help = "The path to the file to refactor. With --stdin, the name to show for the source")]
         file_path: PathBuf,
 
         #[arg(help = "The name of the new function to create")]
         new_fn_name: String,
 
-        #[arg(help = "Index to the start of the function to extract")]
-        start_index: usize,
+        #[arg(help = "Index to the start of the function to extract", required_unless_present_any = ["range", "markers", "item"])]
+        start_index: Option<usize>,
 
-        #[arg(help = "Index to the end of the function to extract")]
-        end_index: usize,
+        #[arg(help = "Index to the end of the function to extract", required_unless_present_any = ["range", "markers", "item"])]
+        end_index: Option<usize>,
 
-        #[arg(short, long, help = "Enable verbose output", action = ArgAction::SetTrue)]
+        #[arg(long, help = "The code to extract as 1-based line:col positions, e.g. 12:5-18:2", conflicts_with_all = ["start_index", "end_index"])]
+        range: Option<String>,
+
+        #[arg(long, help = "How the columns in --range are counted: bytes, chars or utf16", default_value = "bytes")]
+        unit: ColumnUnit,
+
+        #[arg(long, help = "Extract the code between `// rem-extract:start [name]` and `// rem-extract:end` comments (or `$0` markers), optionally only the start marker with the given name", num_args = 0..=1, default_missing_value = "", conflicts_with_all = ["start_index", "end_index", "range"])]
+        markers: Option<String>,
+
+        #[arg(long, help = "Extract from the function with this path, e.g. crate::parser::Parser::parse_expr", requires = "stmts", conflicts_with_all = ["start_index", "end_index", "range", "markers"])]
+        item: Option<String>,
+
+        #[arg(long, help = "The statements of --item to extract: a 0-based range such as 2..=5, or tail for the tail expression", requires = "item")]
+        stmts: Option<StmtTarget>,
+
+        #[arg(long, help = "Use r#name for keywords and add a numeric suffix if the name is taken, instead of failing", action = ArgAction::SetTrue)]
+        name_fallback: bool,
+
+        #[arg(long, help = "Grow or shrink the selection to the nearest range that can be extracted", action = ArgAction::SetTrue)]
+        snap: bool,
+
+        #[arg(long, help = "Read the source from stdin, and analyse it on its own rather than as part of a Cargo project", action = ArgAction::SetTrue, conflicts_with = "in_place")]
+        stdin: bool,
+
+        #[arg(long, help = "With --stdin, the edition to parse the source with: 2015, 2018, 2021 or 2024", default_value = "2021", value_parser = parse_edition, requires = "stdin")]
+        edition: Edition,
+
+        #[arg(short = 'i', long, help = "Write the result back to the file (atomically) instead of printing it", action = ArgAction::SetTrue)]
+        in_place: bool,
+
+        #[arg(long, help = "With --in-place, keep a copy of the original file as <file>.orig", action = ArgAction::SetTrue, requires = "in_place")]
+        backup: bool,
+
+        #[arg(long, help = "Print a unified diff of the changes instead of the whole file", action = ArgAction::SetTrue)]
+        diff: bool,
+
+        #[arg(long, help = "Color the --diff output", action = ArgAction::SetTrue, requires = "diff")]
+        color: bool,
+
+        #[arg(long, help = "The output format. json prints a single object with the result or the error", value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        #[arg(short, long, help = "Print each phase of the extraction and how long it took", action = ArgAction::SetTrue)]
         verbose: bool,
+
+        #[command(flatten)]
+        cargo: CargoArgs,
+    },
+
+    // Run many extractions from a JSONL file, loading each workspace once
+    Batch {
+        #[arg(help = "The JSONL file of requests, or - to read them from stdin")]
+        requests: String,
+
+        #[arg(short, long, help = "Write the JSONL results to this file instead of stdout")]
+        output: Option<String>,
+
+        #[arg(short, long, help = "Write the changes back to each file once all of its requests have run", action = ArgAction::SetTrue)]
+        in_place: bool,
+
+        #[command(flatten)]
+        cargo: CargoArgs,
+    },
+
+    // Serve JSON-RPC requests over stdin/stdout, keeping workspaces loaded
+    Serve {
+        #[command(flatten)]
+        cargo: CargoArgs,
+    },
+
+    // Run a Language Server offering extract method as a code action
+    Lsp {
+        #[command(flatten)]
+        cargo: CargoArgs,
     },
 
     // Test the extraction process
@@ -44,3 +120,95 @@ pub enum EXTRACTCommands {
         spammy: bool,
     },
 }
+
+/// How the workspace is loaded
+#[derive(Args, Clone, Debug)]
+pub struct CargoArgs {
+    #[arg(long, help = "Features to activate, separated by commas", value_delimiter = ',')]
+    pub features: Vec<String>,
+
+    #[arg(long, help = "Activate all features", action = ArgAction::SetTrue)]
+    pub all_features: bool,
+
+    #[arg(long, help = "Don't activate the default features", action = ArgAction::SetTrue)]
+    pub no_default_features: bool,
+
+    #[arg(long, help = "The target triple to analyse for, e.g. wasm32-unknown-unknown")]
+    pub target: Option<String>,
+
+    #[arg(long = "cfg", help = "An extra cfg to enable, as name or name=\"value\". Can be repeated")]
+    pub cfgs: Vec<String>,
+
+    #[arg(long = "env", help = "An extra environment variable for cargo, as KEY=VALUE. Can be repeated", value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
+    #[arg(long = "cargo-arg", help = "An extra argument for the cargo invocations, e.g. --locked. Can be repeated", allow_hyphen_values = true)]
+    pub cargo_args: Vec<String>,
+
+    #[arg(long, help = "The target directory for the cargo invocations")]
+    pub target_dir: Option<PathBuf>,
+
+    #[arg(long, help = "Load the sysroot, so types from std can be resolved", action = ArgAction::SetTrue)]
+    pub sysroot: bool,
+
+    #[arg(long, help = "Expand proc macros with the sysroot's rust-analyzer-proc-macro-srv (from the rust-analyzer component). Runs the build scripts, like --trusted", action = ArgAction::SetTrue, conflicts_with = "proc_macro_server")]
+    pub proc_macros: bool,
+
+    #[arg(long, help = "Expand proc macros with the proc-macro server at this path. Runs the build scripts, like --trusted")]
+    pub proc_macro_server: Option<PathBuf>,
+
+    #[arg(long, help = "Trust the workspace: run its build scripts (cargo check) so OUT_DIR and generated code resolve. Without it they aren't run", action = ArgAction::SetTrue)]
+    pub trusted: bool,
+
+    #[arg(long, help = "Only load the package that owns the file and its dependencies, not the whole workspace", action = ArgAction::SetTrue)]
+    pub scoped: bool,
+}
+
+impl CargoArgs {
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            features: self.features.clone(),
+            all_features: self.all_features,
+            no_default_features: self.no_default_features,
+            target: self.target.clone(),
+            cfgs: self.cfgs.clone(),
+            extra_env: self.env.iter().cloned().collect::<HashMap<String, String>>(),
+            extra_args: self.cargo_args.clone(),
+            target_dir: self.target_dir.clone(),
+            sysroot: self.sysroot,
+            proc_macros: match (&self.proc_macro_server, self.proc_macros) {
+                (Some(path), _) => ProcMacros::Server(path.clone()),
+                (None, true) => ProcMacros::Sysroot,
+                (None, false) => ProcMacros::Disabled,
+            },
+            build_scripts: if self.trusted {
+                BuildScripts::Run
+            } else {
+                BuildScripts::Skip
+            },
+            scoped: self.scoped,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn parse_edition(edition: &str) -> Result<Edition, String> {
+    match edition {
+        "2015" => Ok(Edition::Edition2015),
+        "2018" => Ok(Edition::Edition2018),
+        "2021" => Ok(Edition::Edition2021),
+        "2024" => Ok(Edition::Edition2024),
+        _ => Err(format!("unknown edition `{}`", edition)),
+    }
+}
+
+fn parse_env(env: &str) -> Result<(String, String), String> {
+    env.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, found `{}`", env))
+}
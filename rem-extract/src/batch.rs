@@ -0,0 +1,449 @@
+//! Batch extraction from JSONL requests.
+//!
+//! Each line of the input is one request:
+//!
+//! ```json
+//! {"id": 1, "file": "src/main.rs", "name": "helper", "start": 120, "end": 180}
+//! {"id": 2, "file": "src/main.rs", "range": "12:5-18:2", "unit": "utf16"}
+//! {"id": 3, "file": "src/lib.rs", "markers": "helper"}
+//! {"id": 4, "file": "src/lib.rs", "item": "crate::parser::parse", "stmts": "2..=5"}
+//! ```
+//!
+//! `snap` and `name_fallback` can be set on any request, as can `contents`
+//! (unsaved contents of `file`) and `overlays` (unsaved contents of other
+//! files, by path). Requests are grouped by cargo workspace, so each
+//! workspace is only loaded once however many of its members they touch.
+//! Offsets and `line:col` ranges refer to the files as they were before the
+//! batch, and are rebased through the edits of earlier extractions on the
+//! same file. One JSONL result is written per request, in the order of the
+//! requests.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    io::{
+        self,
+        BufRead,
+        Write,
+    },
+    path::PathBuf,
+    time::Instant,
+};
+
+use serde_json::{
+    Map,
+    Value,
+};
+
+use ra_ap_ide::{
+    TextEdit,
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_vfs::AbsPathBuf;
+
+use crate::{
+    config::LoadOptions,
+    error::ExtractionError,
+    extraction::{
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    extraction_utils::{
+        convert_to_abs_path_buf,
+        get_manifest_dir,
+        map_range,
+    },
+    json::{
+        error_to_json,
+        output_to_json,
+    },
+    selection::{
+        ColumnUnit,
+        Selection,
+    },
+    session::{
+        session_root,
+        ExtractionSession,
+    },
+    write::write_in_place,
+};
+
+/// A parsed request. The `id` is echoed back in the result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchRequest {
+    pub id: Value,
+    pub input: ExtractionInput,
+}
+
+/// The outcome of a batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// The exit code of the first failed request (in request order), if any
+    pub exit_code: Option<i32>,
+}
+
+/// A request that has been checked and is waiting to run
+struct Pending {
+    index: usize,
+    input: ExtractionInput,
+}
+
+/// Parses a single JSONL request
+pub fn parse_request( line: &str ) -> Result<BatchRequest, ExtractionError> {
+    let value: Value = serde_json::from_str( line )
+        .map_err( |e| ExtractionError::InvalidRequest( e.to_string() ) )?;
+    let obj: &Map<String, Value> = value
+        .as_object()
+        .ok_or_else( || ExtractionError::InvalidRequest( "expected a JSON object".to_string() ) )?;
+
+    Ok( BatchRequest {
+        id: obj.get( "id" ).cloned().unwrap_or( Value::Null ),
+        input: input_from_json( obj )?,
+    } )
+}
+
+/// Builds an `ExtractionInput` from the fields of a request. Exactly one of
+/// `start`/`end`, `range`, `markers` or `item` selects the code.
+pub fn input_from_json( obj: &Map<String, Value> ) -> Result<ExtractionInput, ExtractionError> {
+    let file: String = str_field( obj, "file" )?
+        .ok_or_else( || ExtractionError::InvalidRequest( "missing `file`".to_string() ) )?;
+    let name: String = str_field( obj, "name" )?.unwrap_or_default();
+    let mut input: ExtractionInput = ExtractionInput::new( &file, &name, 0, 0 );
+    let mut selectors: usize = 0;
+
+    match (u32_field( obj, "start" )?, u32_field( obj, "end" )?) {
+        (Some( start ), Some( end )) => {
+            input.start_idx = start;
+            input.end_idx = end;
+            selectors += 1;
+        },
+        (None, None) => (),
+        _ => return Err( ExtractionError::InvalidRequest( "`start` and `end` must be given together".to_string() ) ),
+    }
+    if let Some( range ) = str_field( obj, "range" )? {
+        let unit: ColumnUnit = match str_field( obj, "unit" )? {
+            Some( unit ) => unit.parse().map_err( ExtractionError::InvalidRequest )?,
+            None => ColumnUnit::default(),
+        };
+        input.selection = Some( Selection::parse_line_col( &range, unit ).map_err( ExtractionError::InvalidRequest )? );
+        selectors += 1;
+    }
+    match obj.get( "markers" ) {
+        None | Some( Value::Null ) | Some( Value::Bool( false ) ) => (),
+        Some( Value::Bool( true ) ) => {
+            input.selection = Some( Selection::Markers { name: None } );
+            selectors += 1;
+        },
+        Some( Value::String( name ) ) => {
+            input.selection = Some( Selection::Markers { name: Some( name.clone() ) } );
+            selectors += 1;
+        },
+        Some( _ ) => return Err( ExtractionError::InvalidRequest( "`markers` must be a bool or a name".to_string() ) ),
+    }
+    if let Some( path ) = str_field( obj, "item" )? {
+        let stmts: String = str_field( obj, "stmts" )?
+            .ok_or_else( || ExtractionError::InvalidRequest( "`item` needs `stmts`".to_string() ) )?;
+        input.selection = Some( Selection::Item {
+            path,
+            target: stmts.parse().map_err( ExtractionError::InvalidRequest )?,
+        } );
+        selectors += 1;
+    }
+    if selectors != 1 {
+        return Err( ExtractionError::InvalidRequest(
+            "expected exactly one of `start`/`end`, `range`, `markers` or `item`".to_string()
+        ) );
+    }
+
+    input.snap = bool_field( obj, "snap" )?.unwrap_or( false );
+    input.allow_name_fallback = bool_field( obj, "name_fallback" )?.unwrap_or( false );
+    input.contents = str_field( obj, "contents" )?;
+    match obj.get( "overlays" ) {
+        None | Some( Value::Null ) => (),
+        Some( Value::Object( overlays ) ) => {
+            for (path, text) in overlays {
+                let text: &str = text
+                    .as_str()
+                    .ok_or_else( || ExtractionError::InvalidRequest( format!( "the overlay for `{}` must be a string", path ) ) )?;
+                input.overlays.insert( path.clone(), text.to_string() );
+            }
+        },
+        Some( _ ) => return Err( ExtractionError::InvalidRequest( "`overlays` must map paths to contents".to_string() ) ),
+    }
+
+    Ok( input )
+}
+
+/// Runs every request read from `reader`, and writes one JSONL result per
+/// request to `writer`. If `in_place` is set, each file is written once all
+/// of its requests have run. Every workspace is loaded with `options`.
+pub fn run_batch<R: BufRead, W: Write>(
+    reader: R,
+    writer: &mut W,
+    in_place: bool,
+    options: &LoadOptions,
+) -> io::Result<BatchSummary> {
+    let mut ids: Vec<Value> = vec![];
+    let mut results: Vec<Option<Value>> = vec![];
+    let mut workspaces: Vec<(PathBuf, Vec<Pending>)> = vec![];
+    // Finding the workspace root runs cargo, so it is only done once per package
+    let mut roots: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    for line in reader.lines() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let index: usize = results.len();
+        results.push( None );
+
+        let parsed: Result<(BatchRequest, PathBuf), ExtractionError> = parse_request( &line )
+            .and_then( |request| {
+                let abs_path: AbsPathBuf = convert_to_abs_path_buf( &request.input.file_path )
+                    .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+                let manifest_dir: PathBuf = get_manifest_dir( &PathBuf::from( abs_path.as_str() ) )?;
+                let workspace: PathBuf = match roots.get( &manifest_dir ) {
+                    Some( root ) => root.clone(),
+                    None => {
+                        let root: PathBuf = session_root( &manifest_dir, options )?;
+                        roots.insert( manifest_dir, root.clone() );
+                        root
+                    },
+                };
+                Ok( ( request, workspace ) )
+            } );
+        match parsed {
+            Ok( ( request, workspace ) ) => {
+                ids.push( request.id );
+                let pending: Pending = Pending { index, input: request.input };
+                match workspaces.iter_mut().find( |(root, _)| *root == workspace ) {
+                    Some( (_, requests) ) => requests.push( pending ),
+                    None => workspaces.push( ( workspace, vec![ pending ] ) ),
+                }
+            },
+            Err( e ) => {
+                ids.push( Value::Null );
+                results[index] = Some( error_to_json( &e, Default::default() ) );
+            },
+        }
+    }
+
+    for (_, requests) in workspaces {
+        let load_start: Instant = Instant::now();
+        let mut session: ExtractionSession = match ExtractionSession::load_with_options( &requests[0].input.file_path, options ) {
+            Ok( session ) => session,
+            Err( e ) => {
+                let error: Value = error_to_json( &e, load_start.elapsed() );
+                for request in requests {
+                    results[request.index] = Some( error.clone() );
+                }
+                continue;
+            },
+        };
+
+        for (_, file_requests) in group_by_file( requests ) {
+            run_file( &mut session, file_requests, &mut results, in_place );
+        }
+    }
+
+    let mut summary: BatchSummary = BatchSummary { succeeded: 0, failed: 0, exit_code: None };
+    for (index, (id, result)) in ids.into_iter().zip( results ).enumerate() {
+        let mut result: Value = result.unwrap_or( Value::Null );
+        if let Value::Object( obj ) = &mut result {
+            obj.insert( "index".to_string(), Value::from( index ) );
+            obj.insert( "id".to_string(), id );
+        }
+        if result["status"] == "ok" {
+            summary.succeeded += 1;
+        } else {
+            summary.failed += 1;
+            if summary.exit_code.is_none() {
+                summary.exit_code = result["error"]["exit_code"].as_i64().map( |code| code as i32 );
+            }
+        }
+        writeln!( writer, "{}", result )?;
+    }
+
+    Ok( summary )
+}
+
+/// Groups the requests of a workspace by the file they extract from
+fn group_by_file( requests: Vec<Pending> ) -> Vec<(String, Vec<Pending>)> {
+    let mut files: Vec<(String, Vec<Pending>)> = vec![];
+    for request in requests {
+        let file: String = convert_to_abs_path_buf( &request.input.file_path )
+            .map( |path| path.to_string() )
+            .unwrap_or_else( |_| request.input.file_path.clone() );
+        match files.iter_mut().find( |(path, _)| *path == file ) {
+            Some( (_, requests) ) => requests.push( request ),
+            None => files.push( ( file, vec![ request ] ) ),
+        }
+    }
+    files
+}
+
+/// Runs the requests for a single file.
+/// Offset requests run first, from the end of the file backwards, so that the
+/// new functions (which are inserted after their callers) move as little of
+/// the remaining code as possible. Their ranges are still rebased through the
+/// earlier edits, as imports can be added at the top of the file.
+/// Marker and item requests are found in the current text, so they run last.
+fn run_file(
+    session: &mut ExtractionSession,
+    mut requests: Vec<Pending>,
+    results: &mut [Option<Value>],
+    in_place: bool,
+) {
+    // `line:col` ranges refer to the original text, so they are turned into
+    // offsets up front
+    let original: Option<String> = fs::read_to_string( &requests[0].input.file_path ).ok();
+    requests.retain_mut( |request| {
+        let text: Option<&String> = request.input.contents.as_ref().or( original.as_ref() );
+        if let (Some( selection @ Selection::LineCol { .. } ), Some( text )) = (&request.input.selection, text) {
+            match selection.resolve( text ) {
+                Ok( resolved ) => {
+                    ( request.input.start_idx, request.input.end_idx ) = resolved.range;
+                    request.input.selection = None;
+                },
+                Err( e ) => {
+                    results[request.index] = Some( error_to_json( &e, Default::default() ) );
+                    return false;
+                },
+            }
+        }
+        true
+    } );
+    requests.sort_by_key( |request| match request.input.selection {
+        None => ( 0, Reverse( request.input.start_idx ), request.index ),
+        Some( _ ) => ( 1, Reverse( 0 ), request.index ),
+    } );
+
+    let mut applied: Vec<TextEdit> = vec![];
+    let mut last: Option<(usize, ExtractionOutput)> = None;
+    for mut request in requests {
+        let start: Instant = Instant::now();
+        if request.input.selection.is_none() {
+            match rebase( ( request.input.start_idx, request.input.end_idx ), &applied ) {
+                Some( ( start_idx, end_idx ) ) => {
+                    request.input.start_idx = start_idx;
+                    request.input.end_idx = end_idx;
+                },
+                None => {
+                    let error: ExtractionError = ExtractionError::SelectionConflict(
+                        request.input.start_idx,
+                        request.input.end_idx,
+                    );
+                    results[request.index] = Some( error_to_json( &error, start.elapsed() ) );
+                    continue;
+                },
+            }
+        }
+
+        match session.extract( &request.input ) {
+            Ok( output ) => {
+                applied.extend( output.edits.iter().cloned() );
+                results[request.index] = Some( output_to_json( &output ) );
+                last = Some( ( request.index, output ) );
+            },
+            Err( e ) => results[request.index] = Some( error_to_json( &e, start.elapsed() ) ),
+        }
+    }
+
+    // The last output holds every edit made to the file
+    if let (true, Some( ( index, output ) )) = (in_place, last) {
+        if let Err( e ) = write_in_place( &output, false ) {
+            results[index] = Some( error_to_json( &e, Default::default() ) );
+        }
+    }
+}
+
+/// Maps a range in the original text through the edits made since. Returns
+/// `None` if an earlier edit changed part of the range.
+fn rebase( range: (u32, u32), edits: &[TextEdit] ) -> Option<(u32, u32)> {
+    let mut range: TextRange = TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) );
+    for edit in edits {
+        range = map_range( range, edit )?;
+    }
+    Some( ( range.start().into(), range.end().into() ) )
+}
+
+pub(crate) fn str_field( obj: &Map<String, Value>, key: &str ) -> Result<Option<String>, ExtractionError> {
+    match obj.get( key ) {
+        None | Some( Value::Null ) => Ok( None ),
+        Some( Value::String( s ) ) => Ok( Some( s.clone() ) ),
+        Some( _ ) => Err( ExtractionError::InvalidRequest( format!( "`{}` must be a string", key ) ) ),
+    }
+}
+
+fn u32_field( obj: &Map<String, Value>, key: &str ) -> Result<Option<u32>, ExtractionError> {
+    match obj.get( key ) {
+        None | Some( Value::Null ) => Ok( None ),
+        Some( value ) => value
+            .as_u64()
+            .and_then( |n| u32::try_from( n ).ok() )
+            .map( Some )
+            .ok_or_else( || ExtractionError::InvalidRequest( format!( "`{}` must be an offset", key ) ) ),
+    }
+}
+
+fn bool_field( obj: &Map<String, Value>, key: &str ) -> Result<Option<bool>, ExtractionError> {
+    match obj.get( key ) {
+        None | Some( Value::Null ) => Ok( None ),
+        Some( Value::Bool( b ) ) => Ok( Some( *b ) ),
+        Some( _ ) => Err( ExtractionError::InvalidRequest( format!( "`{}` must be a bool", key ) ) ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request: BatchRequest = parse_request(
+            r#"{"id": 7, "file": "src/main.rs", "name": "helper", "start": 10, "end": 20, "snap": true}"#
+        ).unwrap();
+        assert_eq!( request.id, Value::from( 7 ) );
+        assert_eq!( request.input.new_fn_name, "helper" );
+        assert_eq!( ( request.input.start_idx, request.input.end_idx ), ( 10, 20 ) );
+        assert!( request.input.snap );
+        assert!( request.input.contents.is_none() );
+
+        let request: BatchRequest = parse_request(
+            r#"{"file": "src/main.rs", "markers": true, "contents": "fn main() {}", "overlays": {"src/lib.rs": ""}}"#
+        ).unwrap();
+        assert_eq!( request.input.contents.as_deref(), Some( "fn main() {}" ) );
+        assert_eq!( request.input.overlays.get( "src/lib.rs" ).map( String::as_str ), Some( "" ) );
+
+        let request: BatchRequest = parse_request( r#"{"file": "src/main.rs", "item": "crate::f", "stmts": "tail"}"# ).unwrap();
+        assert!( matches!( request.input.selection, Some( Selection::Item { .. } ) ) );
+
+        // No selector, or more than one
+        assert!( parse_request( r#"{"file": "src/main.rs"}"# ).is_err() );
+        assert!( parse_request( r#"{"file": "src/main.rs", "start": 1, "end": 2, "markers": true}"# ).is_err() );
+        assert!( parse_request( "not json" ).is_err() );
+
+        // Half of a `start`/`end` pair
+        match parse_request( r#"{"file": "src/main.rs", "start": 1}"# ) {
+            Err( ExtractionError::InvalidRequest( message ) ) => assert!( message.contains( "together" ), "{}", message ),
+            other => panic!( "{:?}", other ),
+        }
+    }
+
+    #[test]
+    fn test_rebase() {
+        // An import added at the top of the file, and a function appended
+        let edits: Vec<TextEdit> = vec![
+            TextEdit::insert( TextSize::new( 0 ), "use a::b;\n".to_string() ),
+            TextEdit::replace( TextRange::new( TextSize::new( 50 ), TextSize::new( 60 ) ), "fun_name()".to_string() ),
+        ];
+        assert_eq!( rebase( ( 10, 20 ), &edits ), Some( ( 20, 30 ) ) );
+        // Overlaps the replaced text
+        assert_eq!( rebase( ( 45, 55 ), &edits[..1] ).and_then( |range| rebase( range, &edits[1..] ) ), None );
+    }
+}
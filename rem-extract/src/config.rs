@@ -0,0 +1,210 @@
+//! Options for loading a workspace, mapped onto the `CargoConfig` that
+//! rust-analyzer loads it with.
+//!
+//! Without them, code behind `#[cfg(feature = "...")]` or a target specific
+//! cfg is inactive, so extracting from it fails or gives unresolved types.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+};
+
+use camino::Utf8PathBuf;
+
+use ra_ap_cfg::{
+    CfgAtom,
+    CfgDiff,
+};
+
+use ra_ap_intern::Symbol;
+
+use ra_ap_project_model::{
+    CargoConfig,
+    CargoFeatures,
+    CfgOverrides,
+    RustLibSource,
+};
+
+use crate::error::ExtractionError;
+
+/// How a workspace is loaded. The default matches a plain `cargo check`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Features to activate, in addition to the default features
+    pub features: Vec<String>,
+    pub all_features: bool,
+    pub no_default_features: bool,
+    /// The target triple to analyse for, e.g. `wasm32-unknown-unknown`
+    pub target: Option<String>,
+    /// Extra cfgs to enable in every crate, as `name` or `name="value"`
+    pub cfgs: Vec<String>,
+    /// Extra environment variables for `cargo` and the build scripts
+    pub extra_env: HashMap<String, String>,
+    /// Extra arguments for the `cargo` invocations, e.g. `--locked`
+    pub extra_args: Vec<String>,
+    /// The target directory for the `cargo` invocations
+    pub target_dir: Option<PathBuf>,
+    /// Load the sysroot, so types from `std` can be resolved
+    pub sysroot: bool,
+    /// Expand proc macros (derives and attributes such as `#[tokio::main]`)
+    /// with this server. The proc-macro dylibs are found by running the build
+    /// scripts, so this runs them too, whatever `build_scripts` says.
+    pub proc_macros: ProcMacros,
+    /// Whether build scripts are run to find each crate's `OUT_DIR`
+    pub build_scripts: BuildScripts,
+    /// Keep only the package that owns the target file and its dependencies,
+    /// rather than every member of the workspace
+    pub scoped: bool,
+}
+
+/// Whether the build scripts of the workspace are run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BuildScripts {
+    /// Build scripts aren't run. Loading is faster and runs no code from the
+    /// workspace, but `OUT_DIR` is unknown, so `include!`s from it and
+    /// generated cfgs are unresolved.
+    #[default]
+    Skip,
+    /// Build scripts are run with `cargo check`. They can run arbitrary code,
+    /// so only do this for trusted workspaces.
+    Run,
+}
+
+/// Where the proc-macro server comes from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ProcMacros {
+    /// Proc macros aren't expanded, and anything they produce is unknown
+    #[default]
+    Disabled,
+    /// The `rust-analyzer-proc-macro-srv` shipped with the sysroot (by the
+    /// `rust-analyzer` component). Loads the sysroot.
+    Sysroot,
+    /// A proc-macro server at the given path
+    Server(PathBuf),
+}
+
+impl LoadOptions {
+    /// Whether the build scripts are run (`cargo check`) when loading: when
+    /// asked to, or when proc macros are expanded, since the paths of the
+    /// proc-macro dylibs come from that run
+    pub fn runs_build_scripts( &self ) -> bool {
+        self.build_scripts == BuildScripts::Run || self.proc_macros != ProcMacros::Disabled
+    }
+
+    /// Builds the `CargoConfig` to load the workspace with
+    pub fn cargo_config( &self ) -> Result<CargoConfig, ExtractionError> {
+        let features: CargoFeatures = if self.all_features {
+            CargoFeatures::All
+        } else {
+            CargoFeatures::Selected {
+                features: self.features.clone(),
+                no_default_features: self.no_default_features,
+            }
+        };
+
+        let cfgs: Vec<CfgAtom> = self.cfgs
+            .iter()
+            .map( String::as_str )
+            .map( parse_cfg )
+            .collect::<Result<Vec<CfgAtom>, ExtractionError>>()?;
+        let global: CfgDiff = CfgDiff::new( cfgs, vec![] )
+            .ok_or_else( || ExtractionError::InvalidCfg( "a cfg is given more than once".to_string() ) )?;
+
+        let target_dir: Option<Utf8PathBuf> = match &self.target_dir {
+            Some( dir ) => Some(
+                Utf8PathBuf::from_path_buf( dir.clone() )
+                    .map_err( |dir| ExtractionError::InvalidPath( dir.display().to_string() ) )?
+            ),
+            None => None,
+        };
+
+        Ok( CargoConfig {
+            features,
+            target: self.target.clone(),
+            sysroot: if self.sysroot || self.proc_macros == ProcMacros::Sysroot {
+                Some( RustLibSource::Discover )
+            } else {
+                None
+            },
+            cfg_overrides: CfgOverrides {
+                global,
+                selective: Default::default(),
+            },
+            extra_args: self.extra_args.clone(),
+            extra_env: self.extra_env.clone().into_iter().collect(),
+            target_dir,
+            ..CargoConfig::default()
+        } )
+    }
+}
+
+/// Parses a cfg given as `name`, `name=value` or `name="value"`
+pub fn parse_cfg( cfg: &str ) -> Result<CfgAtom, ExtractionError> {
+    let is_ident = |s: &str| !s.is_empty() && s.chars().all( |c| c.is_alphanumeric() || c == '_' );
+    match cfg.split_once( '=' ) {
+        Some( (key, value) ) => {
+            let key: &str = key.trim();
+            let value: &str = value.trim();
+            let value: &str = value
+                .strip_prefix( '"' )
+                .and_then( |value| value.strip_suffix( '"' ) )
+                .unwrap_or( value );
+            if !is_ident( key ) {
+                return Err( ExtractionError::InvalidCfg( cfg.to_string() ) );
+            }
+            Ok( CfgAtom::KeyValue { key: Symbol::intern( key ), value: Symbol::intern( value ) } )
+        },
+        None if is_ident( cfg.trim() ) => Ok( CfgAtom::Flag( Symbol::intern( cfg.trim() ) ) ),
+        None => Err( ExtractionError::InvalidCfg( cfg.to_string() ) ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cfg() {
+        assert_eq!( parse_cfg( "loom" ).unwrap(), CfgAtom::Flag( Symbol::intern( "loom" ) ) );
+        assert_eq!(
+            parse_cfg( "feature=\"serde\"" ).unwrap(),
+            CfgAtom::KeyValue { key: Symbol::intern( "feature" ), value: Symbol::intern( "serde" ) },
+        );
+        assert!( parse_cfg( "" ).is_err() );
+        assert!( parse_cfg( "not a cfg" ).is_err() );
+    }
+
+    #[test]
+    fn test_cargo_config() {
+        let options: LoadOptions = LoadOptions {
+            features: vec![ "serde".to_string() ],
+            no_default_features: true,
+            target: Some( "wasm32-unknown-unknown".to_string() ),
+            extra_env: HashMap::from( [ ( "RUSTFLAGS".to_string(), "-Dwarnings".to_string() ) ] ),
+            ..Default::default()
+        };
+        let config: CargoConfig = options.cargo_config().unwrap();
+        assert_eq!(
+            config.features,
+            CargoFeatures::Selected { features: vec![ "serde".to_string() ], no_default_features: true },
+        );
+        assert_eq!( config.target.as_deref(), Some( "wasm32-unknown-unknown" ) );
+        assert_eq!( config.extra_env.get( "RUSTFLAGS" ).map( String::as_str ), Some( "-Dwarnings" ) );
+        assert!( config.sysroot.is_none() );
+
+        let all: LoadOptions = LoadOptions { all_features: true, sysroot: true, ..Default::default() };
+        let config: CargoConfig = all.cargo_config().unwrap();
+        assert_eq!( config.features, CargoFeatures::All );
+        assert!( config.sysroot.is_some() );
+
+        // The sysroot's proc-macro server needs the sysroot
+        let proc_macros: LoadOptions = LoadOptions { proc_macros: ProcMacros::Sysroot, ..Default::default() };
+        assert!( proc_macros.cargo_config().unwrap().sysroot.is_some() );
+
+        // Build scripts only run for trusted workspaces, or to find the
+        // proc-macro dylibs
+        assert_eq!( LoadOptions::default().build_scripts, BuildScripts::Skip );
+        assert!( !LoadOptions::default().runs_build_scripts() );
+        assert!( proc_macros.runs_build_scripts() );
+    }
+}
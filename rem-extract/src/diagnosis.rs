@@ -0,0 +1,435 @@
+//! Explains why the extract function assist is not applicable for a selection.
+//! rust-analyzer just doesn't offer the assist when it can't be applied, so
+//! the checks here mirror the ones made by its `extract_function` assist (see
+//! `extraction_target` and `external_control_flow` in `ide-assists`) in order
+//! to report a specific reason.
+
+use std::fmt;
+
+use ra_ap_ide::{
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_syntax::{
+    ast,
+    AstNode,
+    NodeOrToken,
+    SourceFile,
+    SyntaxElement,
+    SyntaxKind::{
+        self,
+        BREAK_EXPR,
+        CLOSURE_EXPR,
+        CONST,
+        CONTINUE_EXPR,
+        FN,
+        FOR_EXPR,
+        IF_EXPR,
+        LABEL,
+        LIFETIME,
+        LOOP_EXPR,
+        MATCH_ARM,
+        RETURN_EXPR,
+        STATIC,
+        TOKEN_TREE,
+        TRY_EXPR,
+        WHILE_EXPR,
+    },
+    SyntaxNode,
+};
+
+/// Why extract function is not applicable for a selection
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionDiagnosis {
+    /// The (trimmed) selection that was checked
+    pub selection: TextRange,
+    pub reason: NotApplicableReason,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotApplicableReason {
+    /// The selection is empty once whitespace is trimmed
+    EmptySelection,
+    /// The selection is not inside a fn, closure, const or static body
+    NoEnclosingFnBody {
+        kind: SyntaxKind,
+        range: TextRange,
+    },
+    /// The selection is inside the token tree of a macro call
+    InsideMacroCall {
+        range: TextRange,
+    },
+    /// The selection starts or ends part way through a statement
+    SplitsStatement {
+        kind: SyntaxKind,
+        range: TextRange,
+    },
+    /// The selection only covers part of an expression, and there is no
+    /// enclosing expression that can be extracted instead
+    PartialExpression {
+        kind: SyntaxKind,
+        range: TextRange,
+    },
+    /// The selection is something other than statements or an expression,
+    /// e.g. an item
+    NotAnExpression {
+        kind: SyntaxKind,
+        range: TextRange,
+    },
+    /// The selection leaves through a combination of `return`, `break`,
+    /// `continue` and `?` that the assist can't express
+    UnsupportedControlFlow {
+        exits: Vec<(SyntaxKind, TextRange)>,
+    },
+    /// A `let` in the selection binds a variable that is used after the
+    /// selection, and the selection also leaves through control flow
+    LetUsedOutside {
+        binding: String,
+        let_range: TextRange,
+        usage_range: TextRange,
+    },
+    /// None of the known rules apply. The covering node is reported instead.
+    Unknown {
+        kind: SyntaxKind,
+        range: TextRange,
+    },
+}
+
+impl fmt::Display for SelectionDiagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "selection {:?}: {}", self.selection, self.reason)
+    }
+}
+
+impl fmt::Display for NotApplicableReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotApplicableReason::EmptySelection => write!(f, "the selection is empty"),
+            NotApplicableReason::NoEnclosingFnBody { kind, range } => write!(f, "the selection is not inside a function body (it is inside {:?} at {:?})", kind, range),
+            NotApplicableReason::InsideMacroCall { range } => write!(f, "the selection is inside the macro call at {:?}, select the whole call instead", range),
+            NotApplicableReason::SplitsStatement { kind, range } => write!(f, "the selection splits the {:?} at {:?}", kind, range),
+            NotApplicableReason::PartialExpression { kind, range } => write!(f, "the selection only partially covers the {:?} at {:?}", kind, range),
+            NotApplicableReason::NotAnExpression { kind, range } => write!(f, "the selected {:?} at {:?} is not an expression or statement", kind, range),
+            NotApplicableReason::UnsupportedControlFlow { exits } => {
+                write!(f, "the selection leaves through an unsupported mix of control flow:")?;
+                for (kind, range) in exits {
+                    write!(f, " {:?} at {:?}", kind, range)?;
+                }
+                Ok(())
+            }
+            NotApplicableReason::LetUsedOutside { binding, let_range, usage_range } => write!(f, "`{}` is bound by the let at {:?} and used after the selection at {:?}, which can't be combined with the control flow in the selection", binding, let_range, usage_range),
+            NotApplicableReason::Unknown { kind, range } => write!(f, "extract function is not available for the {:?} at {:?}", kind, range),
+        }
+    }
+}
+
+/// Works out why extract function is not applicable for the given (trimmed)
+/// selection.
+pub fn diagnose_selection(
+    source_file: &SourceFile,
+    range: (u32, u32),
+) -> SelectionDiagnosis {
+    let selection: TextRange = TextRange::new(
+        TextSize::new(range.0),
+        TextSize::new(range.1),
+    );
+    SelectionDiagnosis {
+        selection,
+        reason: find_reason(source_file, selection),
+    }
+}
+
+fn find_reason(source_file: &SourceFile, selection: TextRange) -> NotApplicableReason {
+    if selection.is_empty() {
+        return NotApplicableReason::EmptySelection;
+    }
+
+    let covering: SyntaxElement = source_file.syntax().covering_element(selection);
+    let node: SyntaxNode = match covering {
+        NodeOrToken::Node(node) => node,
+        NodeOrToken::Token(token) => match token.parent() {
+            Some(parent) => parent,
+            None => return NotApplicableReason::EmptySelection,
+        },
+    };
+
+    // Anything inside a token tree is just tokens to rust-analyzer
+    if let Some(token_tree) = node.ancestors().find(|it| it.kind() == TOKEN_TREE) {
+        let range: TextRange = token_tree
+            .ancestors()
+            .find_map(ast::MacroCall::cast)
+            .map_or(token_tree.text_range(), |call| call.syntax().text_range());
+        return NotApplicableReason::InsideMacroCall { range };
+    }
+
+    if !node.ancestors().any(|it| matches!(it.kind(), FN | CLOSURE_EXPR | CONST | STATIC)) {
+        let outermost: SyntaxNode = node
+            .ancestors()
+            .take_while(|it| it.parent().is_some())
+            .last()
+            .unwrap_or(node.clone());
+        return NotApplicableReason::NoEnclosingFnBody {
+            kind: outermost.kind(),
+            range: outermost.text_range(),
+        };
+    }
+
+    // Work out what would be extracted, following `extraction_target`
+    let body: TextRange = if let Some(stmt) = ast::Stmt::cast(node.clone()) {
+        if let ast::Stmt::Item(item) = stmt {
+            return NotApplicableReason::NotAnExpression {
+                kind: item.syntax().kind(),
+                range: item.syntax().text_range(),
+            };
+        }
+        node.text_range()
+    } else if let Some(stmt_list) = ast::StmtList::cast(node.clone()) {
+        let children: Vec<SyntaxNode> = stmt_list
+            .syntax()
+            .children()
+            .filter(|child| child.text_range().intersect(selection).map_or(false, |it| !it.is_empty()))
+            .collect();
+        if let Some(split) = children.iter().find(|child| !selection.contains_range(child.text_range())) {
+            return NotApplicableReason::SplitsStatement {
+                kind: split.kind(),
+                range: split.text_range(),
+            };
+        }
+        match (children.first(), children.last()) {
+            (Some(first), Some(last)) => first.text_range().cover(last.text_range()),
+            _ => return NotApplicableReason::EmptySelection,
+        }
+    } else if ast::Expr::cast(node.clone()).is_some() && node.text_range() == selection {
+        node.text_range()
+    } else {
+        match node.ancestors().find_map(ast::Expr::cast) {
+            Some(expr) if is_extractable_expr(&expr) => expr.syntax().text_range(),
+            _ => return NotApplicableReason::PartialExpression {
+                kind: node.kind(),
+                range: node.text_range(),
+            },
+        }
+    };
+
+    let exits: Vec<(SyntaxKind, TextRange)> = external_exits(source_file.syntax(), body);
+    if is_unsupported_control_flow(&exits) {
+        return NotApplicableReason::UnsupportedControlFlow { exits };
+    }
+
+    if !exits.is_empty() {
+        if let Some(reason) = let_used_outside(&node, body) {
+            return reason;
+        }
+    }
+
+    NotApplicableReason::Unknown {
+        kind: node.kind(),
+        range: node.text_range(),
+    }
+}
+
+/// Mirrors `FunctionBody::from_expr`: blocks that belong to something else
+/// (fn bodies, `if` branches, ...) and valueless `break`/`return` can't be
+/// extracted on their own
+fn is_extractable_expr(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::BlockExpr(block) => block.is_standalone(),
+        ast::Expr::BreakExpr(it) => it.expr().is_some(),
+        ast::Expr::ReturnExpr(it) => it.expr().is_some(),
+        _ => true,
+    }
+}
+
+/// Finds the `return`, `?`, `break` and `continue` expressions in the body that
+/// leave it
+fn external_exits(root: &SyntaxNode, body: TextRange) -> Vec<(SyntaxKind, TextRange)> {
+    root.descendants()
+        .filter(|it| body.contains_range(it.text_range()))
+        .filter(|it| matches!(it.kind(), RETURN_EXPR | TRY_EXPR | BREAK_EXPR | CONTINUE_EXPR))
+        .filter(|it| leaves_body(it, body))
+        .map(|it| (it.kind(), it.text_range()))
+        .collect()
+}
+
+/// Checks whether a control flow expression transfers control outside the body
+fn leaves_body(exit: &SyntaxNode, body: TextRange) -> bool {
+    let label: Option<String> = exit
+        .children()
+        .find(|it| it.kind() == LIFETIME)
+        .map(|it| it.text().to_string());
+
+    for ancestor in exit.ancestors().skip(1) {
+        if !body.contains_range(ancestor.text_range()) {
+            return true;
+        }
+        match ancestor.kind() {
+            // `return` and `?` in a closure or nested fn stay inside it
+            CLOSURE_EXPR | FN => return false,
+            LOOP_EXPR | WHILE_EXPR | FOR_EXPR if matches!(exit.kind(), BREAK_EXPR | CONTINUE_EXPR) => {
+                let loop_label: Option<String> = ancestor
+                    .children()
+                    .find(|it| it.kind() == LABEL)
+                    .and_then(|it| it.children().find(|it| it.kind() == LIFETIME))
+                    .map(|it| it.text().to_string());
+                if label.is_none() || label == loop_label {
+                    return false;
+                }
+            }
+            _ => (),
+        }
+    }
+    true
+}
+
+/// The combinations of control flow that `external_control_flow` refuses
+fn is_unsupported_control_flow(exits: &[(SyntaxKind, TextRange)]) -> bool {
+    let has = |kind: SyntaxKind| exits.iter().any(|(it, _)| *it == kind);
+    let try_expr: bool = has(TRY_EXPR);
+    let ret_expr: bool = has(RETURN_EXPR);
+    let break_expr: bool = has(BREAK_EXPR);
+    let continue_expr: bool = has(CONTINUE_EXPR);
+
+    (try_expr && (break_expr || continue_expr))
+        || (ret_expr && (break_expr || continue_expr))
+        || (break_expr && continue_expr)
+}
+
+/// Looks for a variable bound by a `let` in the body that is used after it,
+/// within the enclosing function. Uses that refer to another binding with the
+/// same name are skipped.
+fn let_used_outside(node: &SyntaxNode, body: TextRange) -> Option<NotApplicableReason> {
+    let container: SyntaxNode = node
+        .ancestors()
+        .find(|it| matches!(it.kind(), FN | CLOSURE_EXPR | CONST | STATIC))?;
+
+    container
+        .descendants()
+        .filter_map(ast::LetStmt::cast)
+        .filter(|let_stmt| body.contains_range(let_stmt.syntax().text_range()))
+        .flat_map(|let_stmt| {
+            let_stmt
+                .pat()
+                .into_iter()
+                .flat_map(|pat| pat.syntax().descendants().filter_map(ast::IdentPat::cast).collect::<Vec<_>>())
+                .filter_map(move |ident| ident.name().map(|name| (name.text().to_string(), let_stmt.clone())))
+        })
+        .find_map(|(binding, let_stmt)| {
+            container
+                .descendants()
+                .filter_map(ast::NameRef::cast)
+                .filter(|name_ref| name_ref.syntax().text_range().start() >= body.end())
+                .filter(|name_ref| name_ref.text().as_str() == binding)
+                .find(|name_ref| refers_to_let(name_ref, &let_stmt))
+                .map(|usage| NotApplicableReason::LetUsedOutside {
+                    binding: binding.clone(),
+                    let_range: let_stmt.syntax().text_range(),
+                    usage_range: usage.syntax().text_range(),
+                })
+        })
+}
+
+/// Checks whether a name used after `let_stmt` refers to a binding of that
+/// `let`, rather than to something that shadows it. This only looks at the
+/// syntax: the name has to be in the block the `let` is in, and no pattern
+/// binding the same name may be in scope at the name after the `let`.
+fn refers_to_let(name_ref: &ast::NameRef, let_stmt: &ast::LetStmt) -> bool {
+    let usage: TextRange = name_ref.syntax().text_range();
+    let Some(block) = let_stmt.syntax().parent() else {
+        return false;
+    };
+    if !block.text_range().contains_range(usage) {
+        return false;
+    }
+
+    let name: String = name_ref.text().to_string();
+    !block
+        .descendants()
+        .filter_map(ast::IdentPat::cast)
+        .filter(|pat| pat.name().map_or(false, |it| it.text().as_str() == name))
+        .filter(|pat| pat.syntax().text_range().start() >= let_stmt.syntax().text_range().end())
+        .filter_map(|pat| pattern_scope(&pat))
+        .any(|scope| scope.contains_range(usage))
+}
+
+/// The range in which the binding of a pattern can be used: the rest of the
+/// block after a `let`, or the closure, match arm, loop, `if` or fn that
+/// introduces it
+fn pattern_scope(pat: &ast::IdentPat) -> Option<TextRange> {
+    for ancestor in pat.syntax().ancestors().skip(1) {
+        if let Some(let_stmt) = ast::LetStmt::cast(ancestor.clone()) {
+            let block: SyntaxNode = let_stmt.syntax().parent()?;
+            return Some(TextRange::new(let_stmt.syntax().text_range().end(), block.text_range().end()));
+        }
+        if matches!(ancestor.kind(), CLOSURE_EXPR | MATCH_ARM | FOR_EXPR | IF_EXPR | WHILE_EXPR | FN) {
+            return Some(ancestor.text_range());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_ap_syntax::Edition;
+
+    fn diagnose(text: &str, selected: &str) -> NotApplicableReason {
+        let start: usize = text.find(selected).unwrap();
+        let source_file: SourceFile = SourceFile::parse(text, Edition::CURRENT).tree();
+        diagnose_selection(&source_file, (start as u32, (start + selected.len()) as u32)).reason
+    }
+
+    #[test]
+    fn test_diagnose_inside_macro_call() {
+        let reason = diagnose("fn main() { println!(\"{}\", 1 + 2); }", "1 + 2");
+        assert!(matches!(reason, NotApplicableReason::InsideMacroCall { .. }), "{:?}", reason);
+    }
+
+    #[test]
+    fn test_diagnose_no_enclosing_fn() {
+        let reason = diagnose("struct Foo { a: u32 }", "a: u32");
+        assert!(matches!(reason, NotApplicableReason::NoEnclosingFnBody { .. }), "{:?}", reason);
+    }
+
+    #[test]
+    fn test_diagnose_splits_statement() {
+        let reason = diagnose("fn main() {\n    let a = 1;\n    let b = a + 2;\n}", "let a = 1;\n    let b");
+        assert!(matches!(reason, NotApplicableReason::SplitsStatement { kind: SyntaxKind::LET_STMT, .. }), "{:?}", reason);
+    }
+
+    #[test]
+    fn test_diagnose_unsupported_control_flow() {
+        let text: &str = "fn main() {\n    loop {\n        if true { return; }\n        break;\n    }\n}";
+        let reason = diagnose(text, "if true { return; }\n        break;");
+        assert!(matches!(reason, NotApplicableReason::UnsupportedControlFlow { .. }), "{:?}", reason);
+    }
+
+    #[test]
+    fn test_diagnose_let_used_outside() {
+        let text: &str = "fn f(x: i32) -> i32 {\n    if x > 10 { return 0; }\n    let b = x * 2;\n    let c = b + 1;\n    c\n}";
+        let reason = diagnose(text, "if x > 10 { return 0; }\n    let b = x * 2;");
+        match reason {
+            NotApplicableReason::LetUsedOutside { binding, let_range, usage_range } => {
+                assert_eq!(binding, "b");
+                assert_eq!(&text[let_range], "let b = x * 2;");
+                assert_eq!(usage_range.start(), TextSize::new(text.find("b + 1").unwrap() as u32));
+            }
+            reason => panic!("{:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_diagnose_let_used_outside_needs_control_flow() {
+        // rust-analyzer returns `b` from the new function and rebinds it
+        let text: &str = "fn f(x: i32) -> i32 {\n    let b = x * 2;\n    let c = b + 1;\n    c\n}";
+        let reason = diagnose(text, "let b = x * 2;");
+        assert!(matches!(reason, NotApplicableReason::Unknown { .. }), "{:?}", reason);
+    }
+
+    #[test]
+    fn test_diagnose_let_used_outside_shadowed() {
+        let text: &str = "fn f(x: i32) -> i32 {\n    if x > 10 { return 0; }\n    let b = x * 2;\n    let b = 5;\n    let c = |b: i32| b + 1;\n    c(b)\n}";
+        let reason = diagnose(text, "if x > 10 { return 0; }\n    let b = x * 2;");
+        assert!(matches!(reason, NotApplicableReason::Unknown { .. }), "{:?}", reason);
+    }
+}
@@ -1,7 +1,23 @@
+use std::error::Error;
 use std::fmt;
 use std::io;
 use syn::Error as SynError;
-use ra_ap_ide_assists::Assist;
+use ra_ap_ide::{
+    Cancelled,
+    TextRange,
+};
+use crate::diagnosis::SelectionDiagnosis;
+use crate::selection::Position;
+
+/// A boxed error coming from the rust-analyzer crates (which use `anyhow`)
+pub type BoxedError = Box<dyn Error + Send + Sync>;
+
+/// Where an existing item is defined
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemLocation {
+    pub file_path: String,
+    pub range: TextRange,
+}
 
 #[derive(Debug)]
 pub enum ExtractionError {
@@ -12,10 +28,41 @@ pub enum ExtractionError {
     InvalidEndIdx,
     SameIdx,
     InvalidIdxPair,
-    NoExtractFunction(Vec<Assist>),
+    NoExtractFunction(SelectionDiagnosis),
     CommentNotApplicable,
     BracesNotApplicable,
     ParentMethodNotFound,
+    CalleeNotFound,
+    InvalidIdentifier {
+        name: String,
+        reason: &'static str,
+    },
+    ReservedKeyword(String),
+    NameAlreadyDefined {
+        name: String,
+        existing: Option<ItemLocation>,
+    },
+    InvalidPosition {
+        position: Position,
+        reason: &'static str,
+    },
+    InvalidMarkers(String),
+    ItemNotFound(String),
+    InvalidStmtSelection(String),
+    InvalidRequest(String),
+    SelectionConflict(u32, u32),
+    InvalidPath(String),
+    InvalidCfg(String),
+    ManifestLoadFailed(BoxedError),
+    CargoMetadataFailed(BoxedError),
+    SysrootMissing(BoxedError),
+    WorkspaceLoadFailed(BoxedError),
+    ProcMacroServerNotFound(String),
+    ProcMacroServerFailed(String),
+    FileNotInWorkspace(String),
+    AssistResolveFailed(String),
+    FileChangedOnDisk(String),
+    Cancelled(Cancelled),
 }
 
 impl fmt::Display for ExtractionError {
@@ -28,10 +75,157 @@ impl fmt::Display for ExtractionError {
             ExtractionError::InvalidEndIdx => write!(f, "Invalid end index"),
             ExtractionError::SameIdx => write!(f, "Start and end indices are the same"),
             ExtractionError::InvalidIdxPair => write!(f, "Invalid pair of start and end indices"),
-            ExtractionError::NoExtractFunction(assists) => write!(f, "No Extract Function Assist found for the given selection of assists {:?}", assists),
+            ExtractionError::NoExtractFunction(diagnosis) => write!(f, "Extract function is not applicable: {}", diagnosis),
             ExtractionError::CommentNotApplicable => write!(f, "Extraction not applicable for comment"),
             ExtractionError::BracesNotApplicable => write!(f, "Extraction not applicable for braces"),
             ExtractionError::ParentMethodNotFound => write!(f, "Parent method not found"),
+            ExtractionError::CalleeNotFound => write!(f, "Could not locate the extracted function in the output"),
+            ExtractionError::InvalidIdentifier { name, reason } => write!(f, "`{}` is not a valid function name: {}", name, reason),
+            ExtractionError::ReservedKeyword(name) => write!(f, "`{}` is a reserved keyword", name),
+            ExtractionError::NameAlreadyDefined { name, existing: Some(loc) } => write!(f, "`{}` is already defined at {}:{:?}", name, loc.file_path, loc.range),
+            ExtractionError::NameAlreadyDefined { name, existing: None } => write!(f, "`{}` is already defined in this scope", name),
+            ExtractionError::InvalidPosition { position, reason } => write!(f, "Invalid position {}: {}", position, reason),
+            ExtractionError::InvalidMarkers(e) => write!(f, "Invalid selection markers: {}", e),
+            ExtractionError::ItemNotFound(path) => write!(f, "Could not find the function {}", path),
+            ExtractionError::InvalidStmtSelection(e) => write!(f, "Invalid statement selection: {}", e),
+            ExtractionError::InvalidRequest(e) => write!(f, "Invalid batch request: {}", e),
+            ExtractionError::SelectionConflict(start, end) => write!(f, "Selection {}..{} overlaps code changed by an earlier extraction", start, end),
+            ExtractionError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            ExtractionError::InvalidCfg(cfg) => write!(f, "Invalid cfg `{}`, expected `name` or `name=\"value\"`", cfg),
+            ExtractionError::ManifestLoadFailed(e) => write!(f, "Failed to load the project manifest: {}", e),
+            ExtractionError::CargoMetadataFailed(e) => write!(f, "Failed to load the cargo workspace (cargo metadata): {}", e),
+            ExtractionError::SysrootMissing(e) => write!(f, "Failed to load the sysroot: {}", e),
+            ExtractionError::WorkspaceLoadFailed(e) => write!(f, "Failed to load the workspace into the analysis database: {}", e),
+            ExtractionError::ProcMacroServerNotFound(e) => write!(f, "Could not find the sysroot's proc-macro server (install it with `rustup component add rust-analyzer`, or pass --proc-macro-server): {}", e),
+            ExtractionError::ProcMacroServerFailed(path) => write!(f, "The proc-macro server at {} could not be started", path),
+            ExtractionError::FileNotInWorkspace(path) => write!(f, "File is not part of any crate in the workspace: {}", path),
+            ExtractionError::FileChangedOnDisk(path) => write!(f, "{} has changed on disk since it was analysed, not overwriting it", path),
+            ExtractionError::AssistResolveFailed(e) => write!(f, "Failed to resolve the extract function assist: {}", e),
+            ExtractionError::Cancelled(e) => write!(f, "Analysis was cancelled: {}", e),
+        }
+    }
+}
+
+/// Process exit codes used by the CLI for each class of error
+pub mod exit_code {
+    /// Extraction succeeded
+    pub const SUCCESS: i32 = 0;
+    /// An internal error (e.g. the output could not be understood)
+    pub const INTERNAL: i32 = 1;
+    /// Invalid input: offsets, positions, markers, names or paths. Also used
+    /// by clap for invalid arguments.
+    pub const BAD_INPUT: i32 = 2;
+    /// No usable `Cargo.toml` was found, or it could not be loaded
+    pub const MANIFEST: i32 = 3;
+    /// `cargo metadata`, the sysroot or the workspace failed to load
+    pub const WORKSPACE_LOAD: i32 = 4;
+    /// Extract function is not applicable for the selection
+    pub const NOT_APPLICABLE: i32 = 5;
+    /// Reading or writing a file failed
+    pub const IO: i32 = 6;
+}
+
+impl ExtractionError {
+    /// The exit code the CLI uses for this error (see `exit_code`)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExtractionError::InvalidStartIdx
+            | ExtractionError::InvalidEndIdx
+            | ExtractionError::SameIdx
+            | ExtractionError::InvalidIdxPair
+            | ExtractionError::InvalidIdentifier { .. }
+            | ExtractionError::ReservedKeyword(_)
+            | ExtractionError::NameAlreadyDefined { .. }
+            | ExtractionError::InvalidPosition { .. }
+            | ExtractionError::InvalidMarkers(_)
+            | ExtractionError::ItemNotFound(_)
+            | ExtractionError::InvalidStmtSelection(_)
+            | ExtractionError::InvalidRequest(_)
+            | ExtractionError::SelectionConflict(..)
+            | ExtractionError::InvalidPath(_)
+            | ExtractionError::InvalidCfg(_)
+            | ExtractionError::FileNotInWorkspace(_) => exit_code::BAD_INPUT,
+            ExtractionError::InvalidManifest
+            | ExtractionError::ManifestLoadFailed(_) => exit_code::MANIFEST,
+            ExtractionError::CargoMetadataFailed(_)
+            | ExtractionError::SysrootMissing(_)
+            | ExtractionError::WorkspaceLoadFailed(_)
+            | ExtractionError::ProcMacroServerNotFound(_)
+            | ExtractionError::ProcMacroServerFailed(_) => exit_code::WORKSPACE_LOAD,
+            ExtractionError::NoExtractFunction(_)
+            | ExtractionError::CommentNotApplicable
+            | ExtractionError::BracesNotApplicable => exit_code::NOT_APPLICABLE,
+            ExtractionError::Io(_)
+            | ExtractionError::FileChangedOnDisk(_) => exit_code::IO,
+            ExtractionError::Parse(_)
+            | ExtractionError::ParentMethodNotFound
+            | ExtractionError::CalleeNotFound
+            | ExtractionError::AssistResolveFailed(_)
+            | ExtractionError::Cancelled(_) => exit_code::INTERNAL,
+        }
+    }
+
+    /// The name of the variant, for machine readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExtractionError::Io(_) => "Io",
+            ExtractionError::Parse(_) => "Parse",
+            ExtractionError::InvalidManifest => "InvalidManifest",
+            ExtractionError::InvalidStartIdx => "InvalidStartIdx",
+            ExtractionError::InvalidEndIdx => "InvalidEndIdx",
+            ExtractionError::SameIdx => "SameIdx",
+            ExtractionError::InvalidIdxPair => "InvalidIdxPair",
+            ExtractionError::NoExtractFunction(_) => "NoExtractFunction",
+            ExtractionError::CommentNotApplicable => "CommentNotApplicable",
+            ExtractionError::BracesNotApplicable => "BracesNotApplicable",
+            ExtractionError::ParentMethodNotFound => "ParentMethodNotFound",
+            ExtractionError::CalleeNotFound => "CalleeNotFound",
+            ExtractionError::InvalidIdentifier { .. } => "InvalidIdentifier",
+            ExtractionError::ReservedKeyword(_) => "ReservedKeyword",
+            ExtractionError::NameAlreadyDefined { .. } => "NameAlreadyDefined",
+            ExtractionError::InvalidPosition { .. } => "InvalidPosition",
+            ExtractionError::InvalidMarkers(_) => "InvalidMarkers",
+            ExtractionError::ItemNotFound(_) => "ItemNotFound",
+            ExtractionError::InvalidStmtSelection(_) => "InvalidStmtSelection",
+            ExtractionError::InvalidRequest(_) => "InvalidRequest",
+            ExtractionError::SelectionConflict(..) => "SelectionConflict",
+            ExtractionError::InvalidPath(_) => "InvalidPath",
+            ExtractionError::InvalidCfg(_) => "InvalidCfg",
+            ExtractionError::ManifestLoadFailed(_) => "ManifestLoadFailed",
+            ExtractionError::CargoMetadataFailed(_) => "CargoMetadataFailed",
+            ExtractionError::SysrootMissing(_) => "SysrootMissing",
+            ExtractionError::WorkspaceLoadFailed(_) => "WorkspaceLoadFailed",
+            ExtractionError::ProcMacroServerNotFound(_) => "ProcMacroServerNotFound",
+            ExtractionError::ProcMacroServerFailed(_) => "ProcMacroServerFailed",
+            ExtractionError::FileNotInWorkspace(_) => "FileNotInWorkspace",
+            ExtractionError::AssistResolveFailed(_) => "AssistResolveFailed",
+            ExtractionError::FileChangedOnDisk(_) => "FileChangedOnDisk",
+            ExtractionError::Cancelled(_) => "Cancelled",
+        }
+    }
+
+    /// The range of the source the error refers to, if there is one
+    pub fn range(&self) -> Option<TextRange> {
+        match self {
+            ExtractionError::NoExtractFunction(diagnosis) => Some(diagnosis.selection),
+            ExtractionError::NameAlreadyDefined { existing: Some(loc), .. } => Some(loc.range),
+            ExtractionError::SelectionConflict(start, end) => Some(TextRange::new((*start).into(), (*end).into())),
+            _ => None,
+        }
+    }
+}
+
+impl Error for ExtractionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExtractionError::Io(e) => Some(e),
+            ExtractionError::Parse(e) => Some(e),
+            ExtractionError::ManifestLoadFailed(e)
+            | ExtractionError::CargoMetadataFailed(e)
+            | ExtractionError::SysrootMissing(e)
+            | ExtractionError::WorkspaceLoadFailed(e) => Some(e.as_ref()),
+            ExtractionError::Cancelled(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -46,4 +240,10 @@ impl From<SynError> for ExtractionError {
     fn from(error: SynError) -> Self {
         ExtractionError::Parse(error)
     }
-}
\ No newline at end of file
+}
+
+impl From<Cancelled> for ExtractionError {
+    fn from(error: Cancelled) -> Self {
+        ExtractionError::Cancelled(error)
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     fs,
     time::Instant,
     time::Duration,
@@ -17,12 +18,15 @@ use regex::Regex;
 use crate::{
     extraction::extract_method,
     extraction::ExtractionInput,
+    extraction::ExtractionOutput,
     error::ExtractionError,
+    selection::Selection,
     test_details::TEST_FILES, // Import Test Files Information from test_details.rs
 };
 
 pub struct TestFile<'a> {
     pub input_file: &'a str, // Just the name of the file. It is assumed the file is in ./input/{testname}/src/main.rs, and there is a corresponding file in ./correct_output (./correct_output/{testname}.rs)
+    // If both indices are 0 the range is taken from the markers in the file
     pub start_idx: u32,
     pub end_idx: u32,
 }
@@ -37,6 +41,9 @@ impl TestFile<'_> {
     }
 }
 
+/// The name the fixtures give the extracted function, in place of `fun_name`
+const TEST_FN_NAME: &str = "extracted_fn";
+
 /// A TestInput needs a Path to write to, as part of the testing process is
 /// writing the output to a file. This is not needed for the actual extraction
 pub struct TestInput{
@@ -63,10 +70,23 @@ impl From<&TestFile<'_>> for TestInput {
             .to_string_lossy()
             .to_string();
 
+        // Fixtures that already define `fun_name` keep the generated name, so
+        // the suffixed names (`fun_name1`, ...) are checked as they are. The
+        // rest are given a name of their own, which is applied through the
+        // assist's edit.
+        let defines_fun_name: bool = fs::read_to_string(&file_path)
+            .map(|text| Regex::new(r"\bfun_name\b").unwrap().is_match(&text))
+            .unwrap_or(false);
+        let new_fn_name: String = if defines_fun_name {
+            String::new()
+        } else {
+            TEST_FN_NAME.to_string()
+        };
+
         TestInput {
             file_path,
             output_path,
-            new_fn_name: "fun_name".to_string(),
+            new_fn_name,
             start_idx: test_file.start_idx,
             end_idx: test_file.end_idx,
         }
@@ -82,6 +102,16 @@ impl From<&TestInput> for ExtractionInput {
             new_fn_name: test_input.new_fn_name.clone(),
             start_idx: test_input.start_idx,
             end_idx: test_input.end_idx,
+            allow_name_fallback: false,
+            snap: false,
+            // Fixtures without offsets carry their own markers
+            selection: if test_input.start_idx == 0 && test_input.end_idx == 0 {
+                Some(Selection::Markers { name: None })
+            } else {
+                None
+            },
+            contents: None,
+            overlays: HashMap::new(),
         }
     }
 }
@@ -100,9 +130,27 @@ fn strip_ansi_codes(s: &str) -> String {
 #[allow(dead_code)]
 fn parse_and_compare_ast(output_content: &String, expected_file_path: &str) -> Result<bool, ExtractionError> {
     let expected_content: String = fs::read_to_string(expected_file_path)?;
+    compare_ast(output_content, &expected_content)
+}
 
-    let output_ast: File = parse_file(&output_content)?;
-    let expected_ast: File = parse_file(&expected_content)?;
+/// Compares the output with the expected file. The expected files use the
+/// generated `fun_name`, so it is replaced by the name the test gave, if any.
+fn compare_with_expected(output_content: &str, expected_file_path: &str, new_fn_name: &str) -> Result<bool, ExtractionError> {
+    let expected_content: String = fs::read_to_string(expected_file_path)?;
+    if new_fn_name.is_empty() {
+        return compare_ast(output_content, &expected_content);
+    }
+    let expected_content: String = Regex::new(r"\bfun_name\b")
+        .unwrap()
+        .replace_all(&expected_content, new_fn_name)
+        .to_string();
+    compare_ast(output_content, &expected_content)
+}
+
+/// Compares the ASTs of two sources
+fn compare_ast(output_content: &str, expected_content: &str) -> Result<bool, ExtractionError> {
+    let output_ast: File = parse_file(output_content)?;
+    let expected_ast: File = parse_file(expected_content)?;
 
     // Convert both ASTs back into token stres for comparison
     // FIXME this is sometimes buggy and is convinced that the two files are
@@ -147,6 +195,17 @@ fn remove_all_files(dir: &PathBuf) -> () {
     }
 }
 
+/// Runs the fixture tests, with verbose or spammy output if asked for
+pub fn run_fixture_tests(verbose: bool, spammy: bool) {
+    if verbose {
+        test_verbose();
+    } else if spammy {
+        test_spammy();
+    } else {
+        test();
+    }
+}
+
 #[allow(dead_code)]
 pub fn test() {
     // Clear the output directory before running tests
@@ -184,7 +243,7 @@ pub fn test() {
         let extraction_input: ExtractionInput = ExtractionInput::from(&input);
 
         // Call the extraction method and handle errors
-        let extraction_result: Result<(String, _), ExtractionError> = extract_method(extraction_input);
+        let extraction_result: Result<ExtractionOutput, ExtractionError> = extract_method(extraction_input);
 
         // Measure time taken for extraction
         let test_elapsed_time: Duration = test_start_time.elapsed();
@@ -222,13 +281,13 @@ pub fn test() {
             // Unwrap the result to get the output code (as we know that it is
             // successful)
             // Also write the output code to the output file for later viewing
-            let (extraction_result, _)  = extraction_result.unwrap();
+            let extraction_result: String = extraction_result.unwrap().code;
             fs::write(&output_path, &extraction_result).unwrap();
             extraction_status = "PASSED".green().to_string();
             passed_stage_1 += 1;
 
             // Compare the output file with the expected file's AST
-            match parse_and_compare_ast(&extraction_result, &expected_file_path) {
+            match compare_with_expected(&extraction_result, &expected_file_path, &input.new_fn_name) {
                 Ok(is_identical) => {
                     if is_identical {
                         comparison_status = "PASSED".green().to_string();
@@ -372,7 +431,7 @@ pub fn test_verbose() {
         let extraction_input: ExtractionInput = ExtractionInput::from(&input);
 
         // Call the extraction method and handle errors
-        let extraction_result: Result<(String, _), ExtractionError> = extract_method(extraction_input);
+        let extraction_result: Result<ExtractionOutput, ExtractionError> = extract_method(extraction_input);
 
         // Measure time taken for extraction
         let test_elapsed_time: Duration = test_start_time.elapsed();
@@ -411,13 +470,13 @@ pub fn test_verbose() {
             // Unwrap the result to get the output code (as we know that it is
             // successful)
             // Also write the output code to the output file for later viewing
-            let (extraction_result, _)  = extraction_result.unwrap();
+            let extraction_result: String = extraction_result.unwrap().code;
             fs::write(&output_path, &extraction_result).unwrap();
             extraction_status = "PASSED".green().to_string();
             passed_stage_1 += 1;
 
             // Compare the output file with the expected file's AST
-            match parse_and_compare_ast(&extraction_result, &expected_file_path) {
+            match compare_with_expected(&extraction_result, &expected_file_path, &input.new_fn_name) {
                 Ok(is_identical) => {
                     if is_identical {
                         comparison_status = "PASSED".green().to_string();
@@ -615,7 +674,7 @@ pub fn test_spammy() {
         let extraction_input: ExtractionInput = ExtractionInput::from(&input);
 
         // Call the extraction method and handle errors
-        let extraction_result: Result<(String, _), ExtractionError> = extract_method(extraction_input);
+        let extraction_result: Result<ExtractionOutput, ExtractionError> = extract_method(extraction_input);
 
         // Measure time taken for extraction
         let test_elapsed_time: Duration = test_start_time.elapsed();
@@ -654,13 +713,13 @@ pub fn test_spammy() {
             // Unwrap the result to get the output code (as we know that it is
             // successful)
             // Also write the output code to the output file for later viewing
-            let (extraction_result, _)  = extraction_result.unwrap();
+            let extraction_result: String = extraction_result.unwrap().code;
             fs::write(&output_path, &extraction_result).unwrap();
             extraction_status = "PASSED".green().to_string();
             passed_stage_1 += 1;
 
             // Compare the output file with the expected file's AST
-            match parse_and_compare_ast(&extraction_result, &expected_file_path) {
+            match compare_with_expected(&extraction_result, &expected_file_path, &input.new_fn_name) {
                 Ok(is_identical) => {
                     if is_identical {
                         comparison_status = "PASSED".green().to_string();
@@ -1,62 +1,71 @@
 use std::{
+    collections::HashMap,
     fs,
     io::{
         self,
         ErrorKind
     },
-    path::PathBuf
+    path::PathBuf,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-use ra_ap_ide_db::EditionedFileId;
-use ra_ap_project_model::{
-    CargoConfig,
-    ProjectWorkspace,
-    ProjectManifest,
-};
+use tempfile::TempDir;
 
 use ra_ap_ide::{
-    Analysis,
-    AnalysisHost,
+    TextEdit,
+    TextRange,
     TextSize,
 };
 
 use ra_ap_syntax::{
-    algo, ast::HasName, AstNode, SourceFile
+    algo,
+    ast::{self, HasName},
+    AstNode,
+    Edition,
+    SourceFile,
 };
 
-use ra_ap_hir::Semantics;
-
-use ra_ap_ide_assists::Assist;
-
-use ra_ap_vfs::AbsPathBuf;
-
 use crate::{
+    config::LoadOptions,
     error::ExtractionError,
     extraction_utils::{
-        apply_extract_function,
+        apply_edits,
         convert_to_abs_path_buf,
-        filter_extract_function_assist,
-        get_assists,
-        get_cargo_config,
-        get_cargo_toml,
-        get_manifest_dir,
-        load_project_manifest,
-        load_project_workspace,
-        load_workspace_data,
-        run_analysis,
-        check_braces,
-        check_comment,
-        trim_range,
-        generate_frange,
+        find_inserted_fn,
+        fn_at_range,
+        map_range,
     },
+    patch::unified_diff,
+    selection::Selection,
+    session::ExtractionSession,
 };
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ExtractionInput {
     pub file_path: String,
+    /// The name of the new function. If empty, the name generated by
+    /// rust-analyzer (`fun_name`, `fun_name1`, ...) is kept.
     pub new_fn_name: String,
     pub start_idx: u32,
     pub end_idx: u32,
+    /// If the name is a keyword, use it as a raw identifier (`r#name`), and if
+    /// it is already taken, append a numeric suffix, rather than failing.
+    pub allow_name_fallback: bool,
+    /// If extract function is not applicable for the selection, grow or shrink
+    /// it to the nearest range of whole statements or a whole expression for
+    /// which it is. The range used is reported in `ExtractionOutput::range`.
+    pub snap: bool,
+    /// If set, used instead of `start_idx` and `end_idx`
+    pub selection: Option<Selection>,
+    /// In-memory contents of `file_path` (e.g. an unsaved editor buffer), used
+    /// instead of the file on disk. The file doesn't need to exist on disk,
+    /// but it has to be known to the workspace.
+    pub contents: Option<String>,
+    /// In-memory contents of other files in the workspace, by path
+    pub overlays: HashMap<String, String>,
 }
 
 impl ExtractionInput {
@@ -70,24 +79,234 @@ impl ExtractionInput {
             new_fn_name: new_fn_name.to_string(),
             start_idx,
             end_idx,
+            allow_name_fallback: false,
+            snap: false,
+            selection: None,
+            contents: None,
+            overlays: HashMap::new(),
         }
     }
 
-    #[allow(dead_code)]
     pub fn new_absolute(
         file_path: &str,
         new_fn_name: &str,
         start_idx: u32,
         end_idx: u32,
-    ) -> Self { ExtractionInput {
-            file_path: convert_to_abs_path_buf(file_path).unwrap().as_str().to_string(),
+    ) -> Result<Self, ExtractionError> {
+        let file_path: String = convert_to_abs_path_buf(file_path)
+            .map_err(|path| ExtractionError::InvalidPath(path.to_string()))?
+            .as_str()
+            .to_string();
+        Ok(ExtractionInput {
+            file_path,
             new_fn_name: new_fn_name.to_string(),
             start_idx,
             end_idx,
+            allow_name_fallback: false,
+            snap: false,
+            selection: None,
+            contents: None,
+            overlays: HashMap::new(),
+        })
+    }
+}
+
+/// The result of a successful extraction
+#[derive(Debug, Clone)]
+pub struct ExtractionOutput {
+    /// The full source of the file after the extraction
+    pub code: String,
+    /// The source the edits were applied to (the file without the selected
+    /// markers)
+    pub original_code: String,
+    /// The newly created function
+    pub callee: FunctionInfo,
+    /// The function the code was extracted from
+    pub caller: FunctionInfo,
+    /// The parameters of the new function
+    pub params: Vec<ParamInfo>,
+    /// The return type of the new function, if it has one
+    pub ret_type: Option<String>,
+    /// The edits that were applied to the original file, in order
+    pub edits: Vec<TextEdit>,
+    /// The range of the original file that was extracted, after trimming
+    /// whitespace and snapping
+    pub range: TextRange,
+    /// The file the code was extracted from
+    pub file_path: String,
+    /// `file_path` relative to the root of its workspace, as used in the
+    /// headers of `diff`
+    pub relative_path: String,
+    /// Hash of the text the extraction started from: the input's `contents`
+    /// if it had any, otherwise the file on disk when the extraction ran. Used
+    /// to refuse to overwrite a file that has changed since.
+    pub source_hash: u64,
+    /// How long each phase of the extraction took
+    pub timings: Timings,
+    /// Packages whose build scripts weren't run, so whose `OUT_DIR` was
+    /// unknown to the analysis
+    pub missing_out_dirs: Vec<String>,
+}
+
+/// How long each phase of an extraction took, in the order they ran
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    /// Records the time since `timer` against `phase`, and restarts the timer
+    pub fn lap( &mut self, phase: &'static str, timer: &mut Instant ) {
+        self.phases.push( ( phase, timer.elapsed() ) );
+        *timer = Instant::now();
+    }
+
+    pub fn total( &self ) -> Duration {
+        self.phases.iter().map( |(_, duration)| *duration ).sum()
+    }
+}
+
+impl ExtractionOutput {
+    /// A unified diff of the edits to the original source, suitable for
+    /// `git apply` from the root of the workspace
+    pub fn diff( &self ) -> String {
+        unified_diff( &self.original_code, &self.edits, &self.relative_path, false )
+    }
+}
+
+/// A function in the output code
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub text: String,
+    /// The range of the function in `ExtractionOutput::code`
+    pub range: TextRange,
+}
+
+/// A parameter of the newly created function
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamInfo {
+    pub name: String,
+    pub ty: String,
+    pub mode: PassingMode,
+}
+
+/// How a parameter is passed to the newly created function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassingMode {
+    Value,
+    Ref,
+    MutRef,
+}
+
+impl FunctionInfo {
+    fn from_fn( func: &ast::Fn ) -> Self {
+        FunctionInfo {
+            name: func.name().map_or( "".to_string(), |name| name.text().to_string() ),
+            text: func.syntax().text().to_string(),
+            range: func.syntax().text_range(),
         }
     }
 }
 
+impl ParamInfo {
+    /// Lists the parameters of a function, including any `self` parameter
+    fn from_fn( func: &ast::Fn ) -> Vec<Self> {
+        let mut params: Vec<ParamInfo> = Vec::new();
+        let Some( param_list ) = func.param_list() else {
+            return params;
+        };
+
+        if let Some( self_param ) = param_list.self_param() {
+            let mode: PassingMode = match ( self_param.amp_token(), self_param.mut_token() ) {
+                ( Some(_), Some(_) ) => PassingMode::MutRef,
+                ( Some(_), None ) => PassingMode::Ref,
+                ( None, _ ) => PassingMode::Value,
+            };
+            params.push( ParamInfo {
+                name: "self".to_string(),
+                ty: self_param.syntax().text().to_string(),
+                mode,
+            } );
+        }
+
+        for param in param_list.params() {
+            let ty: Option<ast::Type> = param.ty();
+            let mode: PassingMode = match &ty {
+                Some( ast::Type::RefType( ref_ty ) ) if ref_ty.mut_token().is_some() => PassingMode::MutRef,
+                Some( ast::Type::RefType( _ ) ) => PassingMode::Ref,
+                _ => PassingMode::Value,
+            };
+            params.push( ParamInfo {
+                name: param.pat().map_or( "".to_string(), |pat| pat.syntax().text().to_string() ),
+                ty: ty.map_or( "".to_string(), |ty| ty.syntax().text().to_string() ),
+                mode,
+            } );
+        }
+
+        params
+    }
+}
+
+/// Builds the `ExtractionOutput` from the original file and the edits that were
+/// applied to it. The callee is located through the text inserted by the
+/// assist, and both functions are tracked through the later fixup edits, so no
+/// name lookup is involved.
+pub(crate) fn build_output(
+    source_file: &SourceFile,
+    range: (u32, u32),
+    code: String,
+    edits: Vec<TextEdit>,
+    edition: Edition,
+    file_path: &str,
+    source_hash: u64,
+) -> Result<ExtractionOutput, ExtractionError> {
+    let caller_fn: ast::Fn = parent_fn( source_file, range )
+        .ok_or( ExtractionError::ParentMethodNotFound )?;
+
+    // The first edit is always the assist itself, which inserts the callee
+    let mut text: String = source_file.syntax().text().to_string();
+    let mut callee_range: Option<TextRange> = None;
+    let mut caller_range: Option<TextRange> = Some( caller_fn.syntax().text_range() );
+    for (idx, edit) in edits.iter().enumerate() {
+        text = apply_edits( text, edit.clone(), None );
+        caller_range = caller_range.and_then( |range| map_range( range, edit ) );
+        callee_range = if idx == 0 {
+            let edited: SourceFile = SourceFile::parse( &text, edition ).tree();
+            find_inserted_fn( &edited, edit ).map( |func| func.syntax().text_range() )
+        } else {
+            callee_range.and_then( |range| map_range( range, edit ) )
+        };
+    }
+
+    let output_file: SourceFile = SourceFile::parse( &code, edition ).tree();
+    let callee_fn: ast::Fn = callee_range
+        .and_then( |range| fn_at_range( &output_file, range ) )
+        .ok_or( ExtractionError::CalleeNotFound )?;
+    let caller_fn: ast::Fn = caller_range
+        .and_then( |range| fn_at_range( &output_file, range ) )
+        .ok_or( ExtractionError::ParentMethodNotFound )?;
+
+    Ok( ExtractionOutput {
+        callee: FunctionInfo::from_fn( &callee_fn ),
+        caller: FunctionInfo::from_fn( &caller_fn ),
+        params: ParamInfo::from_fn( &callee_fn ),
+        ret_type: callee_fn
+            .ret_type()
+            .and_then( |ret| ret.ty() )
+            .map( |ty| ty.syntax().text().to_string() ),
+        code,
+        original_code: source_file.syntax().text().to_string(),
+        range: TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) ),
+        file_path: file_path.to_string(),
+        relative_path: file_path.to_string(),
+        source_hash,
+        timings: Timings::default(),
+        missing_out_dirs: vec![],
+        edits,
+    } )
+}
+
 // ========================================
 // Checks for the validity of the input
 // ========================================
@@ -103,26 +322,116 @@ fn check_file_exists(file_path: &str) -> Result<(), ExtractionError> {
     Ok(())
 }
 
+/// Keywords (strict and reserved) that can not be used as a plain function
+/// name in any edition
+const KEYWORDS: &[&str] = &[
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+    "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct",
+    "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "abstract", "become", "box", "do", "final", "macro", "override", "priv",
+    "typeof", "unsized", "virtual", "yield",
+];
+
+/// Keywords from the 2018 edition on. They are plain identifiers in 2015.
+const KEYWORDS_2018: &[&str] = &[ "async", "await", "dyn", "try" ];
+
+/// Keywords from the 2024 edition on
+const KEYWORDS_2024: &[&str] = &[ "gen" ];
+
+/// Whether `ident` is a keyword in the given edition
+fn is_keyword(ident: &str, edition: Edition) -> bool {
+    KEYWORDS.contains(&ident)
+        || (edition >= Edition::Edition2018 && KEYWORDS_2018.contains(&ident))
+        || (edition >= Edition::Edition2024 && KEYWORDS_2024.contains(&ident))
+}
+
+/// Keywords that can't be used as raw identifiers either
+const NON_RAW_KEYWORDS: &[&str] = &[ "crate", "self", "Self", "super" ];
+
+/// Checks that the new function name is a valid, snake_case identifier that is
+/// not a keyword in the edition of the crate. An empty name is accepted, and
+/// means that the name generated by rust-analyzer is kept.
+/// If `allow_fallback` is set, keywords are turned into raw identifiers.
+/// Returns the name to use
+pub(crate) fn check_fn_name(name: &str, edition: Edition, allow_fallback: bool) -> Result<String, ExtractionError> {
+    if name.is_empty() {
+        return Ok(String::new());
+    }
+
+    let invalid = |reason: &'static str| ExtractionError::InvalidIdentifier {
+        name: name.to_string(),
+        reason,
+    };
+
+    let ident: &str = name.strip_prefix("r#").unwrap_or(name);
+    let mut chars = ident.chars();
+    match chars.next() {
+        None => return Err(invalid("it is empty")),
+        Some(c) if !(c == '_' || c.is_alphabetic()) => {
+            return Err(invalid("it must start with a letter or an underscore"));
+        }
+        _ => (),
+    }
+    if !chars.all(|c| c == '_' || c.is_alphanumeric()) {
+        return Err(invalid("it may only contain letters, digits and underscores"));
+    }
+    if ident == "_" {
+        return Err(invalid("`_` is not a valid function name"));
+    }
+    if ident.chars().any(|c| c.is_uppercase()) {
+        return Err(invalid("function names must be snake_case"));
+    }
+
+    if is_keyword(ident, edition) {
+        // `r#name` was asked for explicitly
+        if ident != name && !NON_RAW_KEYWORDS.contains(&ident) {
+            return Ok(name.to_string());
+        }
+        if allow_fallback && !NON_RAW_KEYWORDS.contains(&ident) {
+            return Ok(format!("r#{}", ident));
+        }
+        if allow_fallback {
+            return Ok(format!("{}1", ident));
+        }
+        return Err(ExtractionError::ReservedKeyword(ident.to_string()));
+    }
+
+    Ok(name.to_string())
+}
+
 // Check if the idx pair is valid
-fn check_idx(input: &ExtractionInput) -> Result<(), ExtractionError> {
-    if input.start_idx == input.end_idx {
+pub(crate) fn check_idx(range: &(u32, u32)) -> Result<(), ExtractionError> {
+    let (start_idx, end_idx) = *range;
+    if start_idx == end_idx {
         return Err(ExtractionError::SameIdx);
-    } else if input.start_idx > input.end_idx {
+    } else if start_idx > end_idx {
         return Err(ExtractionError::InvalidIdxPair);
     }
-    if input.start_idx == 0 {
+    if start_idx == 0 {
         return Err(ExtractionError::InvalidStartIdx);
     }
-    if input.end_idx == 0 {
+    if end_idx == 0 {
         return Err(ExtractionError::InvalidEndIdx);
     }
     Ok(())
 }
 
-fn verify_input(input: &ExtractionInput) -> Result<(), ExtractionError> {
-    // Execute each input validation step one by one
-    check_file_exists(&input.file_path)?;
-    check_idx(input)?;
+pub(crate) fn verify_input(input: &ExtractionInput) -> Result<(), ExtractionError> {
+    // Execute each input validation step one by one. In-memory contents stand
+    // in for a file that isn't on disk.
+    if input.contents.is_none() {
+        check_file_exists(&input.file_path)?;
+    }
+    // A selection can only be checked once it has been resolved against the
+    // file text
+    if input.selection.is_none() {
+        check_idx(&(input.start_idx, input.end_idx))?;
+    }
+    // The edition isn't known until the workspace is loaded, so only the
+    // keywords of every edition are rejected here. The session checks the
+    // name again with the crate's edition.
+    check_fn_name(&input.new_fn_name, Edition::Edition2015, input.allow_name_fallback)?;
 
     Ok(())
 }
@@ -132,78 +441,74 @@ fn verify_input(input: &ExtractionInput) -> Result<(), ExtractionError> {
 // ========================================
 
 /// Function to extract the code segment based on cursor positions
-/// If successful, returns the `String` of the output code, followed by a
-/// `String` of the caller method
-pub fn extract_method(input: ExtractionInput) -> Result<(String, String), ExtractionError> {
-
-    // Extract the struct information
-    let input_path: &str = &input.file_path;
-    let callee_name: &str = &input.new_fn_name;
-    let start_idx: u32 = input.start_idx;
-    let end_idx: u32 = input.end_idx;
+/// If successful, returns an `ExtractionOutput` describing the new code
+/// Loads the workspace from scratch on every call. Use an `ExtractionSession`
+/// to perform several extractions against the same workspace.
+pub fn extract_method(input: ExtractionInput) -> Result<ExtractionOutput, ExtractionError> {
+    extract_method_with_options(input, &LoadOptions::default())
+}
 
-    // Convert the input and output path to an `AbsPathBuf`
-    let input_abs_path: AbsPathBuf = convert_to_abs_path_buf(input_path).unwrap();
+/// Like `extract_method`, but loads the workspace with the given options
+pub fn extract_method_with_options(
+    input: ExtractionInput,
+    options: &LoadOptions,
+) -> Result<ExtractionOutput, ExtractionError> {
 
-    // Verify the input data
+    // Verify the input data before paying for the workspace load
     verify_input(&input)?;
 
-    let manifest_dir: PathBuf = get_manifest_dir(
-        &PathBuf::from(input_abs_path.as_str())
-    )?;
-    let cargo_toml: AbsPathBuf = get_cargo_toml( &manifest_dir );
-    // println!("Cargo.toml {:?}", cargo_toml);
-
-    let project_manifest: ProjectManifest = load_project_manifest( &cargo_toml );
-    // println!("Project Manifest {:?}", project_manifest);
+    let mut session: ExtractionSession = ExtractionSession::load_with_options(&input.file_path, options)?;
 
-    let cargo_config: CargoConfig = get_cargo_config( &project_manifest );
-    // println!("Cargo Config {:?}", cargo_config);
-
-    let workspace: ProjectWorkspace = load_project_workspace( &project_manifest, &cargo_config );
-    // println!("Project Workspace {:?}", workspace);
-
-    let (db, vfs) = load_workspace_data(workspace, &cargo_config);
-
-    // Parse the cursor positions into the range
-    let range_: (u32, u32) = (
-        start_idx,
-        end_idx,
-    );
-
-    // Before we go too far, lets do few more quick checks now that we have the
-    // analysis
-    // 1. Check if the function to extract is not just a comment
-    // 2. Check if the function to extract has matching braces
-    // 3. Convert the range to a trimmed range.
-    let sema: Semantics<'_, ra_ap_ide::RootDatabase> = Semantics::new( &db );
-    let frange_: ra_ap_hir::FileRangeWrapper<ra_ap_vfs::FileId> = generate_frange( &input_abs_path, &vfs, range_.clone() );
-    let edition: EditionedFileId = EditionedFileId::current_edition( frange_.file_id );
-    let source_file: SourceFile = sema.parse( edition );
-    let range: (u32, u32) = trim_range( &source_file, &range_ );
-    check_comment( &source_file, &range )?;
-    check_braces( &source_file, &range )?;
-
-    let analysis_host: AnalysisHost = AnalysisHost::with_database( db );
-    let analysis: Analysis = run_analysis( analysis_host );
-
-    let assists: Vec<Assist> = get_assists( &analysis, &vfs, &input_abs_path, range );
-    let assist: Assist = filter_extract_function_assist( assists )?;
+    let mut output: ExtractionOutput = session.extract(&input)?;
+    let load_phases: Vec<(&'static str, Duration)> = session.load_timings().phases.clone();
+    output.timings.phases.splice(0..0, load_phases);
+    Ok(output)
+}
 
+/// The name given to standalone source in an `ExtractionOutput`
+pub const SOURCE_NAME: &str = "<source>";
 
-    let modified_code: String = apply_extract_function(
-        &assist,
-        &input_abs_path,
-        &vfs,
-        &callee_name,
-    )?;
+/// Extracts the code in `range` (byte offsets) of a standalone source string
+/// into a new function called `name`. The source doesn't need to belong to a
+/// Cargo project: it is analysed the way rust-analyzer analyses a detached
+/// file, as a crate of its own with the given edition and the sysroot.
+pub fn extract_from_source(
+    src: &str,
+    range: (u32, u32),
+    name: &str,
+    edition: Edition,
+) -> Result<ExtractionOutput, ExtractionError> {
+    let input: ExtractionInput = ExtractionInput::new(SOURCE_NAME, name, range.0, range.1);
+    extract_input_from_source(src, input, edition)
+}
 
-    let parent_method: String = parent_method(
-        &source_file,
-        range,
-    )?;
-    
-    Ok( (modified_code, parent_method) )
+/// Like `extract_from_source`, but takes an `ExtractionInput` so any selection
+/// can be used. Its `file_path` is only used to name the source in the output.
+pub fn extract_input_from_source(
+    src: &str,
+    mut input: ExtractionInput,
+    edition: Edition,
+) -> Result<ExtractionOutput, ExtractionError> {
+    // The workspace loader reads files from disk, so the source is written to
+    // a temporary directory. Nothing else is: there is no manifest to find.
+    let dir: TempDir = tempfile::tempdir()?;
+    let source_rs: PathBuf = dir.path().join("source.rs");
+    fs::write(&source_rs, src)?;
+
+    let name: String = std::mem::replace(&mut input.file_path, source_rs.to_string_lossy().to_string());
+    verify_input(&input)?;
+    let options: LoadOptions = LoadOptions {
+        sysroot: true,
+        ..LoadOptions::default()
+    };
+    let mut session: ExtractionSession = ExtractionSession::load_detached(&input.file_path, edition, &options)?;
+
+    let mut output: ExtractionOutput = session.extract(&input)?;
+    let load_phases: Vec<(&'static str, Duration)> = session.load_timings().phases.clone();
+    output.timings.phases.splice(0..0, load_phases);
+    output.file_path = name.clone();
+    output.relative_path = name;
+    Ok(output)
 }
 
 /// Gets the caller method, based on the input code and the cursor positions
@@ -213,15 +518,7 @@ pub fn parent_method(
     source_file: &SourceFile,
     range: (u32, u32),
 ) -> Result<String, ExtractionError> {
-    let start: TextSize = TextSize::new(range.0);
-
-    // We want the last function that occurs before the start of the range
-    let node: Option<ra_ap_syntax::ast::Fn> = algo::find_node_at_offset::<ra_ap_syntax::ast::Fn>(
-        source_file.syntax(),
-        start,
-    );
-
-    let fn_name: String = match node {
+    let fn_name: String = match parent_fn( source_file, range ) {
         Some(n) => n.name().map_or("".to_string(), |name| name.text().to_string()),
         None => "".to_string(),
     };
@@ -232,4 +529,63 @@ pub fn parent_method(
 
     Ok( fn_name.trim().to_string() )
 
-}
\ No newline at end of file
+}
+
+/// Gets the `ast::Fn` of the caller method, based on the cursor positions
+pub fn parent_fn(
+    source_file: &SourceFile,
+    range: (u32, u32),
+) -> Option<ast::Fn> {
+    let start: TextSize = TextSize::new(range.0);
+
+    // We want the last function that occurs before the start of the range
+    algo::find_node_at_offset::<ast::Fn>(
+        source_file.syntax(),
+        start,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_fn_name_valid() {
+        assert_eq!(check_fn_name("new_fn", Edition::Edition2021, false).unwrap(), "new_fn");
+        assert_eq!(check_fn_name("", Edition::Edition2021, false).unwrap(), "");
+        assert_eq!(check_fn_name("r#match", Edition::Edition2021, false).unwrap(), "r#match");
+    }
+
+    #[test]
+    fn test_check_fn_name_invalid_identifier() {
+        for name in ["new fn", "1st", "NewFn", "_", "new-fn"] {
+            match check_fn_name(name, Edition::Edition2021, true) {
+                Err(ExtractionError::InvalidIdentifier { .. }) => (),
+                other => panic!("Expected InvalidIdentifier for {:?}, got {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_fn_name_keyword() {
+        match check_fn_name("match", Edition::Edition2021, false) {
+            Err(ExtractionError::ReservedKeyword(name)) => assert_eq!(name, "match"),
+            other => panic!("Expected ReservedKeyword, got {:?}", other),
+        }
+        assert_eq!(check_fn_name("match", Edition::Edition2021, true).unwrap(), "r#match");
+        assert_eq!(check_fn_name("self", Edition::Edition2021, true).unwrap(), "self1");
+    }
+
+    #[test]
+    fn test_check_fn_name_edition_keywords() {
+        // Plain identifiers before the edition that reserved them
+        assert_eq!(check_fn_name("async", Edition::Edition2015, false).unwrap(), "async");
+        assert_eq!(check_fn_name("dyn", Edition::Edition2015, false).unwrap(), "dyn");
+        assert_eq!(check_fn_name("gen", Edition::Edition2021, false).unwrap(), "gen");
+        assert!(matches!(check_fn_name("async", Edition::Edition2018, false), Err(ExtractionError::ReservedKeyword(_))));
+        assert!(matches!(check_fn_name("gen", Edition::Edition2024, false), Err(ExtractionError::ReservedKeyword(_))));
+        assert_eq!(check_fn_name("gen", Edition::Edition2024, true).unwrap(), "r#gen");
+        // Keywords in every edition
+        assert!(matches!(check_fn_name("match", Edition::Edition2015, false), Err(ExtractionError::ReservedKeyword(_))));
+    }
+}
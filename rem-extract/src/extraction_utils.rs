@@ -1,25 +1,60 @@
 //! Utility functions for the rem-extract crate.
 //! At some point these will be merged into rem-utils.
 
-use crate::error::ExtractionError;
+use crate::{
+    config::{
+        LoadOptions,
+        ProcMacros,
+    },
+    error::{
+        ExtractionError,
+        ItemLocation,
+    },
+    diagnosis::diagnose_selection,
+    extraction::parent_fn,
+};
 
 use std::{
+    collections::HashSet,
     env,
     fs,
-    path::PathBuf,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Output,
+    },
+    sync::Arc,
 };
 
 use camino::Utf8PathBuf;
 
+use ra_ap_hir::{
+    db::ExpandDatabase,
+    self as hir,
+    HasSource,
+    ModuleDef,
+    ScopeDef,
+    Semantics,
+};
+
 use ra_ap_project_model::{
     CargoConfig,
+    ManifestPath,
+    Package,
     ProjectWorkspace,
+    ProjectWorkspaceKind,
     ProjectManifest,
+    TargetKind,
+    WorkspaceBuildScripts,
 };
 
 use ra_ap_ide::{
     Analysis,
     AnalysisHost,
+    ChangeWithProcMacros,
     DiagnosticsConfig,
     FileRange,
     RootDatabase,
@@ -33,10 +68,19 @@ use ra_ap_ide::{
 
 use ra_ap_ide_db::{
     imports::insert_use::{
+        insert_use,
         ImportGranularity,
+        ImportScope,
         InsertUseConfig,
         PrefixKind,
     },
+    base_db::{
+        salsa::Durability,
+        CrateGraph,
+        CrateWorkspaceData,
+        SourceDatabase,
+    },
+    EditionedFileId,
     SnippetCap
 };
 
@@ -48,27 +92,52 @@ use ra_ap_ide_assists::{
 };
 
 use ra_ap_vfs::{
+    loader::{
+        Config as LoaderConfig,
+        Entry,
+        Handle,
+        LoadingProgress,
+        Message as LoaderMessage,
+    },
+    AbsPath,
     AbsPathBuf,
+    Change as VfsChange,
     VfsPath,
     Vfs,
     FileId,
 };
 
+use ra_ap_vfs_notify::NotifyHandle;
+
 use ra_ap_load_cargo::{
     LoadCargoConfig,
     ProcMacroServerChoice,
+    ProjectFolders,
+    load_proc_macro,
     load_workspace,
 };
 
+use ra_ap_proc_macro_api::ProcMacroServer;
+
 use ra_ap_parser::{
     T,
-    SyntaxKind::COMMENT,
+    SyntaxKind::{
+        CLOSURE_EXPR,
+        COMMENT,
+        CONST,
+        FN,
+        STATIC,
+    },
 };
 
 use ra_ap_syntax::{
     algo,
+    ast::{self, make, HasAttrs, HasName},
     AstNode,
-    SourceFile
+    Edition,
+    NodeOrToken,
+    SourceFile,
+    SyntaxNode,
 };
 
 
@@ -130,9 +199,10 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
         Ok(abs_path_buf) => Ok(abs_path_buf),
         Err(_) => {
             // Resolve non-absolute path to the current working directory.
-            let current_dir = env::current_dir().expect("Failed to get current directory");
+            let current_dir = env::current_dir()
+                .map_err(|_| Utf8PathBuf::from(path))?;
             let utf8_current_dir = Utf8PathBuf::from_path_buf(current_dir)
-                .expect("Failed to convert current directory to Utf8PathBuf");
+                .map_err(|_| Utf8PathBuf::from(path))?;
 
             // println!("Current dir: {:?}", utf8_current_dir);
             // println!("Current path: {:?}", path);
@@ -143,18 +213,21 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
 
             // Create directories leading to the resolved path if they don't exist
             if let Some(parent) = normalized_path.parent() {
-                fs::create_dir_all(parent).expect("Failed to create directories");
+                fs::create_dir_all(parent).map_err(|_| Utf8PathBuf::from(path))?;
             }
 
             // Attempt to convert the normalized path to AbsPathBuf
-            let abs_path = AbsPathBuf::try_from(normalized_path.to_str().unwrap())
+            let normalized_path_str: &str = normalized_path
+                .to_str()
+                .ok_or_else(|| Utf8PathBuf::from(path))?;
+            let abs_path = AbsPathBuf::try_from(normalized_path_str)
                 .map_err(|e| e); // Return the error if the resolved path is still invalid
             // println!("Resolved path: {:?}", abs_path);
 
             // If the abs_path as a string starts with either a \ or a ? (or some
             // combination), strip it out
 
-            let abs_path_str: String = abs_path.unwrap().to_string();
+            let abs_path_str: String = abs_path?.to_string();
             let abs_path_str: String = abs_path_str
                 .replace(r"\\?\", "");
 
@@ -167,28 +240,65 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
     }
 }
 
+/// Returns the path of `file_path` relative to `root`, with `/` separators as
+/// `git apply` expects. Falls back to `file_path` as given if it isn't under
+/// `root`.
+pub fn relative_path( file_path: &str, root: &Path ) -> String {
+    let abs_path: AbsPathBuf = match convert_to_abs_path_buf( file_path ) {
+        Ok( abs_path ) => abs_path,
+        Err( _ ) => return file_path.to_string(),
+    };
+    match Path::new( abs_path.as_str() ).strip_prefix( root ) {
+        Ok( relative ) => relative
+            .components()
+            .map( |component| component.as_os_str().to_string_lossy() )
+            .collect::<Vec<_>>()
+            .join( "/" ),
+        Err( _ ) => file_path.to_string(),
+    }
+}
+
+/// Returns the root directory of the cargo workspace that the package in
+/// `manifest_dir` belongs to. For a package that isn't a member of a larger
+/// workspace, this is `manifest_dir` itself.
+/// Fails with `CargoMetadataFailed` if cargo can't find the workspace.
+pub fn get_workspace_root( manifest_dir: &PathBuf ) -> Result<PathBuf, ExtractionError> {
+    let output: Output = Command::new( "cargo" )
+        .args( [ "locate-project", "--workspace", "--message-format", "plain", "--manifest-path" ] )
+        .arg( manifest_dir.join( "Cargo.toml" ) )
+        .output()?;
+    if !output.status.success() {
+        let stderr: String = String::from_utf8_lossy( &output.stderr ).trim().to_string();
+        return Err( ExtractionError::CargoMetadataFailed( stderr.into() ) );
+    }
+    let cargo_toml: PathBuf = PathBuf::from( String::from_utf8_lossy( &output.stdout ).trim() );
+    Ok( cargo_toml
+        .parent()
+        .map( Path::to_path_buf )
+        .unwrap_or_else( || manifest_dir.clone() ) )
+}
+
 /// Given a `PathBuf` to a folder, returns the `AbsPathBuf` to the `Cargo.toml`
 /// file in that folder.
-pub fn get_cargo_toml( manifest_dir: &PathBuf ) -> AbsPathBuf {
-    AbsPathBuf::try_from(
-        manifest_dir
-            .join( "Cargo.toml" )
-            .to_str()
-            .unwrap()
-    ).unwrap()
+pub fn get_cargo_toml( manifest_dir: &PathBuf ) -> Result<AbsPathBuf, ExtractionError> {
+    let cargo_toml: PathBuf = manifest_dir.join( "Cargo.toml" );
+    let cargo_toml_str: &str = cargo_toml
+        .to_str()
+        .ok_or_else( || ExtractionError::InvalidPath( cargo_toml.display().to_string() ) )?;
+    AbsPathBuf::try_from( cargo_toml_str )
+        .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )
 }
 
 /// Loads as `ProjectManifest` from the given `AbsPathBuf` to a `Cargo.toml` file.
-pub fn load_project_manifest( cargo_toml: &AbsPathBuf ) -> ProjectManifest {
+pub fn load_project_manifest( cargo_toml: &AbsPathBuf ) -> Result<ProjectManifest, ExtractionError> {
     ProjectManifest::from_manifest_file(
         cargo_toml.clone()
-    ).unwrap()
+    ).map_err( |e| ExtractionError::ManifestLoadFailed( e.into() ) )
 }
 
 /// Loads in the custom cargo configuration
-/// TODO This is currently just the derived default.
-pub fn get_cargo_config( _manifest: &ProjectManifest ) -> CargoConfig {
-    CargoConfig::default()
+pub fn get_cargo_config( options: &LoadOptions ) -> Result<CargoConfig, ExtractionError> {
+    options.cargo_config()
 }
 
 pub fn progress( _message: String ) -> () {
@@ -196,45 +306,334 @@ pub fn progress( _message: String ) -> () {
 }
 
 /// Loads a project workspace from a `ProjectManifest` and `CargoConfig`
+/// Fails with `SysrootMissing` if the config asks for a sysroot that couldn't
+/// be loaded.
 pub fn load_project_workspace(
     project_manifest: &ProjectManifest,
     cargo_config: &CargoConfig,
-) -> ProjectWorkspace {
-    ProjectWorkspace::load(
+) -> Result<ProjectWorkspace, ExtractionError> {
+    let workspace: ProjectWorkspace = ProjectWorkspace::load(
         project_manifest.clone(),
         cargo_config,
         &progress
-    ).unwrap()
+    ).map_err( |e| ExtractionError::CargoMetadataFailed( e.into() ) )?;
+
+    if cargo_config.sysroot.is_some() {
+        // The sysroot only keeps the message of its error, so that is what
+        // becomes the source
+        if let Some( error ) = workspace.sysroot.error() {
+            return Err( ExtractionError::SysrootMissing( error.to_string().into() ) );
+        }
+    }
+
+    Ok( workspace )
+}
+
+/// Loads a workspace for a single file that isn't part of a Cargo project, as
+/// rust-analyzer does for a detached file. There is no `cargo metadata`, only
+/// the sysroot if the config asks for it.
+pub fn load_detached_workspace(
+    file: &AbsPathBuf,
+    cargo_config: &CargoConfig,
+) -> Result<ProjectWorkspace, ExtractionError> {
+    let detached_file: ManifestPath = ManifestPath::try_from( file.clone() )
+        .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+    let workspace: ProjectWorkspace = ProjectWorkspace::load_detached_file( &detached_file, cargo_config )
+        .map_err( |e| ExtractionError::WorkspaceLoadFailed( e.into() ) )?;
+
+    if cargo_config.sysroot.is_some() {
+        if let Some( error ) = workspace.sysroot.error() {
+            return Err( ExtractionError::SysrootMissing( error.to_string().into() ) );
+        }
+    }
+
+    Ok( workspace )
+}
+
+/// Sets the edition of the crate whose root is `file_id`. A detached file is
+/// always loaded with the default edition.
+pub fn set_crate_edition( db: &mut RootDatabase, file_id: FileId, edition: Edition ) {
+    let mut crate_graph: CrateGraph = (*db.crate_graph()).clone();
+    for (_, data) in crate_graph.iter_mut() {
+        if data.root_file_id == file_id {
+            data.edition = edition;
+        }
+    }
+    db.set_crate_graph_with_durability( Arc::new( crate_graph ), Durability::HIGH );
+}
+
+/// Finds the proc-macro server the options ask for, and runs the build
+/// scripts if the options ask for it, or for proc macros. Both loaders only
+/// use the `OUT_DIR`s and proc-macro dylibs the workspace already has.
+fn prepare_workspace(
+    workspace: &mut ProjectWorkspace,
+    cargo_config: &CargoConfig,
+    options: &LoadOptions,
+) -> Result<Option<AbsPathBuf>, ExtractionError> {
+    // The server is found up front, as `load_workspace` only logs a server
+    // that fails to start
+    let proc_macro_server: Option<AbsPathBuf> = match &options.proc_macros {
+        ProcMacros::Disabled => None,
+        ProcMacros::Sysroot => Some(
+            workspace
+                .find_sysroot_proc_macro_srv()
+                .map_err( |e| ExtractionError::ProcMacroServerNotFound( e.to_string() ) )?
+        ),
+        ProcMacros::Server( path ) => Some(
+            convert_to_abs_path_buf( &path.to_string_lossy() )
+                .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?
+        ),
+    };
+
+    if options.runs_build_scripts() {
+        let build_scripts: WorkspaceBuildScripts = workspace
+            .run_build_scripts( cargo_config, &progress )
+            .map_err( |e| ExtractionError::WorkspaceLoadFailed( e.into() ) )?;
+        workspace.set_build_scripts( build_scripts );
+    }
+
+    Ok( proc_macro_server )
 }
 
 /// Loads a `RootDatabase` containing from a `ProjectWorkspace` and `CargoConfig`
+/// The build scripts are run first if the options ask for it, or for proc
+/// macros. If proc macros are enabled, fails with `ProcMacroServerNotFound`
+/// or `ProcMacroServerFailed` if the server can't be found or started.
 pub fn load_workspace_data(
-    workspace: ProjectWorkspace,
+    mut workspace: ProjectWorkspace,
     cargo_config: &CargoConfig,
-) -> (
+    options: &LoadOptions,
+) -> Result<(
     RootDatabase,
     Vfs
-) {
+), ExtractionError> {
+    let proc_macro_server: Option<AbsPathBuf> = prepare_workspace( &mut workspace, cargo_config, options )?;
+
     let load_cargo_config: LoadCargoConfig = LoadCargoConfig {
-        load_out_dirs_from_check: true,
-        with_proc_macro_server: ProcMacroServerChoice::None,
+        load_out_dirs_from_check: options.runs_build_scripts(),
+        with_proc_macro_server: match &proc_macro_server {
+            Some( path ) => ProcMacroServerChoice::Explicit( path.clone() ),
+            None => ProcMacroServerChoice::None,
+        },
         prefill_caches: false,
     };
 
     let (db,
         vfs,
-        _proc_macro
+        proc_macro
     ) = load_workspace(
         workspace,
         &cargo_config.extra_env,
         &load_cargo_config
-    ).unwrap();
+    ).map_err( |e| ExtractionError::WorkspaceLoadFailed( e.into() ) )?;
+
+    if let (Some( path ), None) = (proc_macro_server, proc_macro) {
+        return Err( ExtractionError::ProcMacroServerFailed( path.to_string() ) );
+    }
+
+    Ok( (db, vfs) )
+}
+
+/// The packages of a Cargo workspace that a scoped load keeps: the package in
+/// a manifest directory, and its dependencies
+struct PackageScope {
+    /// The directory of every package in the workspace
+    all_dirs: Vec<PathBuf>,
+    /// The directories of the packages that are kept
+    kept_dirs: HashSet<PathBuf>,
+}
+
+impl PackageScope {
+    /// Returns `None` if the workspace has no package in `manifest_dir`, so
+    /// there is nothing to scope to
+    fn new( workspace: &ProjectWorkspace, manifest_dir: &Path ) -> Option<Self> {
+        let ProjectWorkspaceKind::Cargo { cargo, .. } = &workspace.kind else {
+            return None;
+        };
+        let package_dir = |package: Package| -> PathBuf {
+            PathBuf::from( cargo[package].manifest.parent().as_str() )
+        };
+
+        let root: Package = cargo.packages().find( |&package| package_dir( package ) == manifest_dir )?;
+        let mut kept: HashSet<Package> = HashSet::from( [ root ] );
+        let mut stack: Vec<Package> = vec![ root ];
+        while let Some( package ) = stack.pop() {
+            for dependency in &cargo[package].dependencies {
+                if kept.insert( dependency.pkg ) {
+                    stack.push( dependency.pkg );
+                }
+            }
+        }
+
+        Some( PackageScope {
+            all_dirs: cargo.packages().map( package_dir ).collect(),
+            kept_dirs: kept.into_iter().map( package_dir ).collect(),
+        } )
+    }
+
+    /// Whether a path belongs to a package that isn't kept. A path belongs to
+    /// the package with the deepest directory containing it, so a member
+    /// nested in the directory of the root package is told apart from it.
+    /// Paths outside every package, such as the sysroot, are kept.
+    fn excludes( &self, path: &Path ) -> bool {
+        self.all_dirs
+            .iter()
+            .filter( |dir| path.starts_with( dir ) )
+            .max_by_key( |dir| dir.components().count() )
+            .is_some_and( |dir| !self.kept_dirs.contains( dir ) )
+    }
+}
+
+/// Loads only the package in `manifest_dir` and its dependencies, rather than
+/// every member of the workspace. This is `load_workspace` with the other
+/// packages left out up front: their crates are never added to the crate
+/// graph, and their directories are never loaded into the `Vfs`. The
+/// dependencies come from `cargo metadata`, so that still describes the
+/// whole workspace. Falls back to `load_workspace_data` if `manifest_dir`
+/// isn't a package of the workspace.
+/// Returns the number of crates kept, and the number in the whole workspace.
+pub fn load_scoped_workspace_data(
+    mut workspace: ProjectWorkspace,
+    cargo_config: &CargoConfig,
+    options: &LoadOptions,
+    manifest_dir: &Path,
+) -> Result<(
+    RootDatabase,
+    Vfs,
+    (usize, usize)
+), ExtractionError> {
+    let Some( scope ) = PackageScope::new( &workspace, manifest_dir ) else {
+        let (db, vfs) = load_workspace_data( workspace, cargo_config, options )?;
+        let total: usize = db.crate_graph().len();
+        return Ok( (db, vfs, ( total, total )) );
+    };
+
+    let proc_macro_server: Option<ProcMacroServer> = match prepare_workspace( &mut workspace, cargo_config, options )? {
+        Some( path ) => Some(
+            ProcMacroServer::spawn( &path, &cargo_config.extra_env )
+                .map_err( |_| ExtractionError::ProcMacroServerFailed( path.to_string() ) )?
+        ),
+        None => None,
+    };
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut loader: NotifyHandle = NotifyHandle::spawn( sender );
+    let mut vfs: Vfs = Vfs::default();
+
+    // The crates of the packages left out are counted, but their root files
+    // aren't loaded, so they never make it into the crate graph
+    let mut skipped: usize = 0;
+    let (crate_graph, proc_macro_paths) = workspace.to_crate_graph(
+        &mut |path: &AbsPath| {
+            if scope.excludes( Path::new( path.as_str() ) ) {
+                skipped += 1;
+                return None;
+            }
+            let contents: Option<Vec<u8>> = loader.load_sync( path );
+            let path: VfsPath = VfsPath::from( path.to_path_buf() );
+            vfs.set_file_contents( path.clone(), contents );
+            vfs.file_id( &path )
+        },
+        &cargo_config.extra_env,
+    );
+    let crates: (usize, usize) = ( crate_graph.len(), crate_graph.len() + skipped );
+
+    // The directories of the packages left out aren't loaded either,
+    // including those nested in a directory that is
+    let mut folders: ProjectFolders = ProjectFolders::new( std::slice::from_ref( &workspace ), &[], None );
+    folders.load.retain( |entry| match entry {
+        Entry::Files( files ) => !files.iter().all( |file| scope.excludes( Path::new( file.as_str() ) ) ),
+        Entry::Directories( dirs ) => !dirs.include.iter().all( |dir| scope.excludes( Path::new( dir.as_str() ) ) ),
+    } );
+    for entry in &mut folders.load {
+        if let Entry::Directories( dirs ) = entry {
+            let nested: Vec<AbsPathBuf> = scope.all_dirs
+                .iter()
+                .filter( |dir| dirs.include.iter().any( |include| dir.starts_with( include.as_str() ) ) )
+                .filter( |dir| scope.excludes( dir ) )
+                .filter_map( |dir| convert_to_abs_path_buf( &dir.to_string_lossy() ).ok() )
+                .collect();
+            dirs.exclude.extend( nested );
+        }
+    }
+    loader.set_config( LoaderConfig {
+        load: folders.load,
+        watch: vec![],
+        version: 0,
+    } );
+    for message in receiver {
+        match message {
+            LoaderMessage::Progress { n_done: LoadingProgress::Finished, .. } => break,
+            LoaderMessage::Progress { .. } => (),
+            LoaderMessage::Loaded { files } | LoaderMessage::Changed { files } => {
+                for (path, contents) in files {
+                    vfs.set_file_contents( path.into(), contents );
+                }
+            },
+        }
+    }
+
+    // The same change `load_workspace` makes, so the database is consistent:
+    // the files, the source roots, and the crate graph with its workspace
+    // data and proc macros all describe the kept packages only
+    let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+    for (_, file) in vfs.take_changes() {
+        if let VfsChange::Create( contents, _ ) | VfsChange::Modify( contents, _ ) = file.change {
+            if let Ok( text ) = String::from_utf8( contents ) {
+                change.change_file( file.file_id, Some( text ) );
+            }
+        }
+    }
+    change.set_roots( folders.source_root_config.partition( &vfs ) );
 
-    (db, vfs)
+    let data: Arc<CrateWorkspaceData> = Arc::new( CrateWorkspaceData {
+        data_layout: workspace.target_layout.clone(),
+        toolchain: workspace.toolchain.clone(),
+    } );
+    let ws_data = crate_graph
+        .iter()
+        .map( |krate| ( krate, data.clone() ) )
+        .collect();
+    change.set_crate_graph( crate_graph, ws_data );
+    change.set_proc_macros(
+        proc_macro_paths
+            .into_iter()
+            .filter_map( |(krate, path)| {
+                let server: &ProcMacroServer = proc_macro_server.as_ref()?;
+                let (_, path) = path.ok()?;
+                Some( ( krate, load_proc_macro( server, &path, &[] ) ) )
+            } )
+            .collect()
+    );
+
+    let mut db: RootDatabase = RootDatabase::new( None );
+    db.enable_proc_attr_macros();
+    db.apply_change( change );
+
+    Ok( (db, vfs, crates) )
+}
+
+/// The packages in a workspace that have a build script, so lose their
+/// `OUT_DIR` if build scripts aren't run
+pub fn packages_with_build_scripts( workspace: &ProjectWorkspace ) -> Vec<String> {
+    let ProjectWorkspaceKind::Cargo { cargo, .. } = &workspace.kind else {
+        return vec![];
+    };
+    let mut packages: Vec<String> = cargo
+        .packages()
+        .filter( |&package| cargo[package].targets
+            .iter()
+            .any( |&target| cargo[target].kind == TargetKind::BuildScript )
+        )
+        .map( |package| cargo[package].name.clone() )
+        .collect();
+    packages.sort();
+    packages.dedup();
+    packages
 }
 
 /// Runs the analysis on an AnalysisHost. A wrapper around `AnalysisHost::analysis`
-pub fn run_analysis( host: AnalysisHost ) -> Analysis {
+pub fn run_analysis( host: &AnalysisHost ) -> Analysis {
 
     let analysis: Analysis = host.analysis();
 
@@ -259,12 +658,12 @@ pub fn get_assists (
     vfs: &Vfs,
     input_path: &AbsPathBuf,
     range: (u32, u32), // Tuple of start and end offsets
-) -> Vec<Assist> {
+) -> Result<Vec<Assist>, ExtractionError> {
 
     let assist_config: AssistConfig = generate_assist_config();
     let diagnostics_config: DiagnosticsConfig = generate_diagnostics_config();
     let resolve: AssistResolveStrategy = generate_resolve_strategy();
-    let frange: FileRange = generate_frange(input_path, vfs, range);
+    let frange: FileRange = generate_frange(input_path, vfs, range)?;
 
     // Call the assists_with_fixes method
     let assists: Vec<Assist> = analysis.assists_with_fixes(
@@ -272,9 +671,9 @@ pub fn get_assists (
         &diagnostics_config,
         resolve,
         frange
-    ).unwrap();
+    )?;
 
-    assists
+    Ok( assists )
 }
 
 // Build out the AssistConfig Object
@@ -333,96 +732,126 @@ pub fn generate_frange(
     input_path: &AbsPathBuf,
     vfs: &Vfs,
     range: (u32, u32)
-) -> FileRange {
-    let vfs_path: VfsPath = VfsPath::new_real_path(
-        input_path
-            .as_str()
-            .to_string(),
-    );
-
-    let file_id_: FileId = vfs.file_id( &vfs_path ).unwrap();
+) -> Result<FileRange, ExtractionError> {
+    let file_id_: FileId = get_file_id( input_path, vfs )?;
     let range_: TextRange = TextRange::new(
-        TextSize::try_from( range.0 ).unwrap(),
-        TextSize::try_from( range.1 ).unwrap(),
+        TextSize::new( range.0 ),
+        TextSize::new( range.1 ),
     );
 
     let frange: FileRange = FileRange {
         file_id: file_id_,
         range: range_,
     };
-    frange
+    Ok( frange )
+}
+
+/// Looks up the `FileId` of a file in the `Vfs`
+/// Throws ExtractionError::FileNotInWorkspace if the file wasn't loaded as part
+/// of the workspace
+pub fn get_file_id(
+    input_path: &AbsPathBuf,
+    vfs: &Vfs,
+) -> Result<FileId, ExtractionError> {
+    let vfs_path: VfsPath = VfsPath::new_real_path(
+        input_path
+            .as_str()
+            .to_string(),
+    );
+
+    vfs.file_id( &vfs_path )
+        .ok_or_else( || ExtractionError::FileNotInWorkspace( input_path.to_string() ) )
 }
 
 /// Filter the list of assists to only be the extract_function assist
 /// FIXME This is a hack to get around the fact that the resolve strategy is bugged
 /// and is returning both extract_variable and extract_function
-/// Throws ExtractionError::NoExtractFunction if no assist found
-pub fn filter_extract_function_assist( assists: Vec<Assist> ) -> Result<Assist, ExtractionError> {
-    if let Some(extract_assist) = assists
-        .iter()
+/// Returns `None` if rust-analyzer did not offer extract function. The caller
+/// should then use `diagnosis::diagnose_selection` to work out why.
+pub fn filter_extract_function_assist( assists: Vec<Assist> ) -> Option<Assist> {
+    assists
+        .into_iter()
         .find(|assist| assist.label == "Extract into function")
-        {
-            // Return the found assist
-            Ok(extract_assist.clone())
-        } else {
-            // Return the error
-            Err(ExtractionError::NoExtractFunction( assists ))
-        }
 }
 
 /// Applies the extract_function source change to the given code
-/// Returns the String of the output code
-/// Renames the function from `fun_name` to `callee_name`.
+/// Returns the String of the output code, along with every `TextEdit` that
+/// was applied to get there (in the order they were applied).
+/// Renames the generated function to `callee_name`.
 /// Requires the output path to be an `AbsPathBuf`.
+/// The source is taken from the analysis rather than from disk, so that the
+/// edit is applied to exactly the text the assist was computed against.
 pub fn apply_extract_function(
     assist: &Assist,
     input_path: &AbsPathBuf,
     vfs: &Vfs,
+    analysis: &Analysis,
     callee_name: &str,
-) -> Result<String, ExtractionError> {
-
-    let vfs_in_path: VfsPath = VfsPath::new_real_path(
-        input_path
-            .as_str()
-            .to_string(),
-    );
+    edition: Edition,
+    no_std: bool,
+) -> Result<(String, Vec<TextEdit>), ExtractionError> {
 
     // From here, extract the source change, but apply it to the copied file
     let src_change: SourceChange = assist.source_change
         .as_ref()
-        .unwrap()
+        .ok_or_else( || ExtractionError::AssistResolveFailed(
+            "the assist has no source change".to_string()
+        ) )?
         .clone();
 
-    let in_file_id: FileId = vfs.file_id( &vfs_in_path ).unwrap();
+    let in_file_id: FileId = get_file_id( input_path, vfs )?;
     let (text_edit, maybe_snippet_edit) = src_change.get_source_and_snippet_edit(
         in_file_id
-    ).unwrap();
+    ).ok_or_else( || ExtractionError::AssistResolveFailed(
+        format!( "the assist does not edit {}", input_path )
+    ) )?;
 
-    // Get the source from the input file
-    let src_path: PathBuf = vfs_to_pathbuf( &vfs_in_path );
-    let text: String = fs::read_to_string( &src_path ).unwrap();
+    // Get the source of the input file from the database
+    let text: String = analysis.file_text( in_file_id )?.to_string();
     let edited_text: String = apply_edits(
         text,
         text_edit.clone(),
         maybe_snippet_edit.clone(),
     );
-
-    // Rename the function from fun_name to NEW_FUNCTION_NAME using a search and
-    // replace on the output file
-    let renamed_text: String = rename_function(
-        edited_text,
-        "fun_name",
+    let mut edits: Vec<TextEdit> = vec![ text_edit.clone() ];
+
+    // Rename the generated function (and its call site) to NEW_FUNCTION_NAME.
+    // The function is located through the text the assist inserted, so
+    // anything else that happens to contain `fun_name` is left alone.
+    let edited_file: SourceFile = SourceFile::parse( &edited_text, edition ).tree();
+    let rename_edit: TextEdit = rename_extracted_function(
+        &edited_file,
+        text_edit,
         callee_name,
+    )?;
+    let callee_range: TextRange = find_inserted_fn( &edited_file, text_edit )
+        .and_then( |callee| map_range( callee.syntax().text_range(), &rename_edit ) )
+        .ok_or( ExtractionError::CalleeNotFound )?;
+    let renamed_text: String = apply_edits( edited_text, rename_edit.clone(), None );
+    edits.push( rename_edit );
+
+    // Ensure that the module containing the new function imports ControlFlow
+    // if the function uses it
+    let renamed_file: SourceFile = SourceFile::parse( &renamed_text, edition ).tree();
+    let callee: ast::Fn = fn_at_range( &renamed_file, callee_range )
+        .ok_or( ExtractionError::CalleeNotFound )?;
+    let controlflow_edit: TextEdit = fixup_controlflow(
+        &renamed_text,
+        &callee,
+        &generate_assist_config().insert_use,
+        no_std,
     );
+    let fixed_cf_text: String = apply_edits( renamed_text, controlflow_edit.clone(), None );
+    edits.push( controlflow_edit );
 
-    // Ensure that the output file imports std::ops::ControlFlow if it uses it
-    let fixed_cf_text: String = fixup_controlflow( renamed_text );
+    // Don't report the fixups that turned out to be no-ops
+    edits.retain( |edit| !edit.is_empty() );
 
-    Ok( fixed_cf_text)
+    Ok( (fixed_cf_text, edits) )
 }
 
 /// Applies the edits to a given set of source code (as a String)
-fn apply_edits(
+pub fn apply_edits(
     text: String,
     text_edit: TextEdit,
     maybe_snippet_edit: Option<SnippetEdit>,
@@ -438,24 +867,98 @@ fn apply_edits(
     text
 }
 
-// Rename a function in a file using a search and replace
-fn rename_function(
-    text: String,
-    old_name: &str,
+/// Renames the function inserted by the assist, along with its call site.
+/// rust-analyzer picks the generated name (`fun_name`, `fun_name1`, ...), so
+/// the name is read off the inserted `ast::Fn` rather than assumed. Only name
+/// references inside the text inserted by the assist are renamed, which is
+/// where the call that replaced the selection lives.
+/// The `source_file` must be the parsed result of applying `text_edit`.
+/// Returns the `TextEdit` that performs the rename
+fn rename_extracted_function(
+    source_file: &SourceFile,
+    text_edit: &TextEdit,
     new_name: &str,
-) -> String {
-    let mut text = text; // Make the text mutable
-    let old_name: String = old_name.to_string();
-    let new_name: String = new_name.to_string();
-    text = text.replace( &old_name, &new_name );
-    text
+) -> Result<TextEdit, ExtractionError> {
+    let callee: ast::Fn = find_inserted_fn( source_file, text_edit )
+        .ok_or( ExtractionError::CalleeNotFound )?;
+    let callee_name: ast::Name = callee.name()
+        .ok_or( ExtractionError::CalleeNotFound )?;
+    let old_name: String = callee_name.text().to_string();
+
+    // An empty name keeps the name rust-analyzer generated
+    let mut builder = TextEdit::builder();
+    if new_name.is_empty() || old_name == new_name {
+        return Ok( builder.finish() );
+    }
+    builder.replace( callee_name.syntax().text_range(), new_name.to_string() );
+
+    let callee_range: TextRange = callee.syntax().text_range();
+    let inserted: Vec<TextRange> = inserted_ranges( text_edit );
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::NameRef::cast )
+        .filter( |name_ref| name_ref.text().as_str() == old_name )
+        .map( |name_ref| name_ref.syntax().text_range() )
+        .filter( |range| !callee_range.contains_range( *range ) )
+        .filter( |range| inserted.iter().any( |ins| ins.contains_range( *range ) ) )
+        .for_each( |range| builder.replace( range, new_name.to_string() ) );
+
+    Ok( builder.finish() )
+}
+
+/// Returns the ranges, in the edited text, of the text inserted by each indel
+/// of the given `TextEdit`
+pub fn inserted_ranges( text_edit: &TextEdit ) -> Vec<TextRange> {
+    let mut ranges: Vec<TextRange> = Vec::new();
+    let mut shift: i64 = 0;
+    for indel in text_edit.iter() {
+        let start: i64 = u32::from( indel.delete.start() ) as i64 + shift;
+        ranges.push( TextRange::at(
+            TextSize::new( start as u32 ),
+            TextSize::of( indel.insert.as_str() ),
+        ) );
+        shift += u32::from( TextSize::of( indel.insert.as_str() ) ) as i64;
+        shift -= u32::from( indel.delete.len() ) as i64;
+    }
+    ranges
+}
+
+/// Maps a range in the text before an edit to the same range in the text after
+/// the edit. Returns `None` if either end of the range was deleted by the edit.
+pub fn map_range( range: TextRange, text_edit: &TextEdit ) -> Option<TextRange> {
+    let start: TextSize = text_edit.apply_to_offset( range.start() )?;
+    let end: TextSize = text_edit.apply_to_offset( range.end() )?;
+    Some( TextRange::new( start, end ) )
+}
+
+/// Finds the function that was inserted by the given edit
+/// The `source_file` must be the parsed result of applying `text_edit`.
+pub fn find_inserted_fn(
+    source_file: &SourceFile,
+    text_edit: &TextEdit,
+) -> Option<ast::Fn> {
+    let inserted: Vec<TextRange> = inserted_ranges( text_edit );
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Fn::cast )
+        .find( |func| {
+            let fn_range: TextRange = func.syntax().text_range();
+            inserted.iter().any( |range| range.contains_range( fn_range ) )
+        } )
 }
 
-/// Converts a `VfsPath` to a `PathBuf`
-fn vfs_to_pathbuf( vfs_path: &VfsPath ) -> PathBuf {
-    let path_str = vfs_path.to_string();
-    // println!("{}", path_str);
-    PathBuf::from( path_str )
+/// Finds the function whose syntax node spans exactly the given range
+pub fn fn_at_range(
+    source_file: &SourceFile,
+    range: TextRange,
+) -> Option<ast::Fn> {
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Fn::cast )
+        .find( |func| func.syntax().text_range() == range )
 }
 
 /// Checks that there is some input to the function that isn't a comment
@@ -509,6 +1012,143 @@ pub fn check_braces(
 
 }
 
+/// Checks that the new function name doesn't collide with anything already
+/// defined where rust-analyzer will insert the function. The function is
+/// inserted next to the caller, so for a caller inside an `impl` the
+/// associated items of the type's inherent impls are checked, otherwise the
+/// value namespace of the caller's scope is checked, along with the locals in
+/// scope at the call site, which would shadow the call.
+/// If `allow_fallback` is set, a numeric suffix is appended until the name is
+/// free.
+/// Returns the name to use
+pub fn check_name_in_scope(
+    sema: &Semantics<'_, RootDatabase>,
+    vfs: &Vfs,
+    source_file: &SourceFile,
+    range: (u32, u32),
+    name: &str,
+    allow_fallback: bool,
+) -> Result<String, ExtractionError> {
+    // An empty name keeps the generated name, which is always free
+    if name.is_empty() {
+        return Ok(String::new());
+    }
+    let Some( caller ) = parent_fn( source_file, range ) else {
+        return Ok( name.to_string() );
+    };
+
+    let mut candidate: String = name.to_string();
+    let mut suffix: u32 = 0;
+    loop {
+        let plain: &str = candidate.strip_prefix( "r#" ).unwrap_or( &candidate );
+        let existing: Option<Option<ItemLocation>> = match caller.syntax().ancestors().find_map( ast::Impl::cast ) {
+            Some( impl_ ) => find_assoc_item( sema, vfs, &impl_, plain ),
+            None => find_local_at( sema, &caller, range.0, plain )
+                .or_else( || find_value_in_scope( sema, vfs, &caller, plain ) ),
+        };
+
+        match existing {
+            None => return Ok( candidate ),
+            Some( existing ) if !allow_fallback => {
+                return Err( ExtractionError::NameAlreadyDefined {
+                    name: candidate,
+                    existing,
+                } );
+            }
+            Some( _ ) => {
+                suffix += 1;
+                candidate = format!( "{}{}", name, suffix );
+            }
+        }
+    }
+}
+
+/// Looks for an associated item with the given name in any inherent impl of
+/// the impl's self type.
+/// Returns `Some` (with the location of the item if it is known) if found
+fn find_assoc_item(
+    sema: &Semantics<'_, RootDatabase>,
+    vfs: &Vfs,
+    impl_: &ast::Impl,
+    name: &str,
+) -> Option<Option<ItemLocation>> {
+    let impl_def: hir::Impl = sema.to_def( impl_ )?;
+    let self_ty: hir::Type = impl_def.self_ty( sema.db );
+    hir::Impl::all_for_type( sema.db, self_ty )
+        .into_iter()
+        .filter( |other| other.trait_( sema.db ).is_none() )
+        .flat_map( |other| other.items( sema.db ) )
+        .find( |item| item.name( sema.db ).map_or( false, |n| n.as_str() == name ) )
+        .map( |item| match item {
+            hir::AssocItem::Function( it ) => item_location( sema, vfs, it ),
+            hir::AssocItem::Const( it ) => item_location( sema, vfs, it ),
+            hir::AssocItem::TypeAlias( it ) => item_location( sema, vfs, it ),
+        } )
+}
+
+/// Looks for a local (a `let` binding or a parameter of the caller) with the
+/// given name in scope at the call site, which would shadow the new function.
+/// Returns `Some(None)` if found, as locals have no item location
+fn find_local_at(
+    sema: &Semantics<'_, RootDatabase>,
+    caller: &ast::Fn,
+    offset: u32,
+    name: &str,
+) -> Option<Option<ItemLocation>> {
+    let scope = sema.scope_at_offset( caller.syntax(), TextSize::new( offset ) )?;
+    let mut found: bool = false;
+    scope.process_all_names( &mut |scope_name, def| {
+        if scope_name.as_str() == name && matches!( def, ScopeDef::Local( _ ) ) {
+            found = true;
+        }
+    } );
+    found.then_some( None )
+}
+
+/// Looks for a function, const or static with the given name in the scope of
+/// the caller.
+/// Returns `Some` (with the location of the item if it is known) if found
+fn find_value_in_scope(
+    sema: &Semantics<'_, RootDatabase>,
+    vfs: &Vfs,
+    caller: &ast::Fn,
+    name: &str,
+) -> Option<Option<ItemLocation>> {
+    let scope = sema.scope( caller.syntax() )?;
+    let mut found: Option<Option<ItemLocation>> = None;
+    scope.process_all_names( &mut |scope_name, def| {
+        if found.is_some() || scope_name.as_str() != name {
+            return;
+        }
+        found = match def {
+            ScopeDef::ModuleDef( ModuleDef::Function( it ) ) => Some( item_location( sema, vfs, it ) ),
+            ScopeDef::ModuleDef( ModuleDef::Const( it ) ) => Some( item_location( sema, vfs, it ) ),
+            ScopeDef::ModuleDef( ModuleDef::Static( it ) ) => Some( item_location( sema, vfs, it ) ),
+            _ => None,
+        };
+    } );
+    found
+}
+
+/// Gets the file and range an item is defined at. Returns `None` for items
+/// that come from a macro expansion.
+fn item_location<T>(
+    sema: &Semantics<'_, RootDatabase>,
+    vfs: &Vfs,
+    def: T,
+) -> Option<ItemLocation>
+where
+    T: HasSource,
+    T::Ast: AstNode,
+{
+    let src = def.source( sema.db )?;
+    let file_id = src.file_id.file_id()?;
+    Some( ItemLocation {
+        file_path: vfs.file_path( file_id.file_id() ).to_string(),
+        range: src.value.syntax().text_range(),
+    } )
+}
+
 /// Trims the selected range to remove any whitespace
 pub fn trim_range(
     source_file: &SourceFile,
@@ -539,16 +1179,220 @@ pub fn trim_range(
 
 }
 
-/// Checks if a file contains a reference to ControlFlow::, and if so, adds  use
-/// std::ops::ControlFlow;\n\n to the start of the file, saving it back to the input path
-/// Returns the new text with the ControlFlow:: reference fixed up
-fn fixup_controlflow( text: String, ) -> String {
-    let mut text: String = text; // Make the text mutable
-    let controlflow_ref: &str = "ControlFlow::";
-    if text.contains( controlflow_ref ) {
-        text = format!("use std::ops::ControlFlow;\n\n{}", text);
+/// Finds the nearest range to the selection for which extract function is
+/// applicable. The selection itself is tried first, then the candidates from
+/// `snap_candidates`.
+/// Returns `ExtractionError::NoExtractFunction` (diagnosing the original
+/// selection) if none of them work.
+pub fn snap_range(
+    analysis: &Analysis,
+    vfs: &Vfs,
+    input_path: &AbsPathBuf,
+    source_file: &SourceFile,
+    range: (u32, u32),
+) -> Result<(u32, u32), ExtractionError> {
+    let candidates: Vec<(u32, u32)> = std::iter::once( range )
+        .chain( snap_candidates( source_file, &range ) )
+        .collect();
+
+    for candidate in candidates {
+        let assists: Vec<Assist> = get_assists( analysis, vfs, input_path, candidate )?;
+        if filter_extract_function_assist( assists ).is_some() {
+            return Ok( candidate );
+        }
     }
-    text
+
+    Err( ExtractionError::NoExtractFunction( diagnose_selection( source_file, range ) ) )
+}
+
+/// Lists the ranges the selection could be snapped to, nearest first.
+/// These are the whole statements the selection touches (growing it), the
+/// whole statements inside it (shrinking it), and every enclosing statement or
+/// expression up to the enclosing function.
+/// Nearest means the smallest total movement of the start and end offsets.
+pub fn snap_candidates(
+    source_file: &SourceFile,
+    range: &(u32, u32)
+) -> Vec<(u32, u32)> {
+    let selection: TextRange = TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) );
+    let node: SyntaxNode = match source_file.syntax().covering_element( selection ) {
+        NodeOrToken::Node( node ) => node,
+        NodeOrToken::Token( token ) => match token.parent() {
+            Some( parent ) => parent,
+            None => return vec![],
+        },
+    };
+
+    let mut candidates: Vec<TextRange> = vec![];
+    for ancestor in node.ancestors() {
+        if let Some( stmt_list ) = ast::StmtList::cast( ancestor.clone() ) {
+            let children: Vec<TextRange> = stmt_list
+                .syntax()
+                .children()
+                .map( |child| child.text_range() )
+                .collect();
+            let touched: Vec<TextRange> = children
+                .iter()
+                .filter( |child| child.intersect( selection ).map_or( false, |it| !it.is_empty() ) )
+                .copied()
+                .collect();
+            let inside: Vec<TextRange> = children
+                .iter()
+                .filter( |child| selection.contains_range( **child ) )
+                .copied()
+                .collect();
+            for stmts in [touched, inside] {
+                if let (Some( first ), Some( last )) = (stmts.first(), stmts.last()) {
+                    candidates.push( first.cover( *last ) );
+                }
+            }
+        } else if ast::Expr::can_cast( ancestor.kind() ) || ast::Stmt::can_cast( ancestor.kind() ) {
+            candidates.push( ancestor.text_range() );
+        }
+
+        if matches!( ancestor.kind(), FN | CLOSURE_EXPR | CONST | STATIC ) {
+            break;
+        }
+    }
+
+    let distance = |candidate: &TextRange| -> u32 {
+        u32::from( candidate.start() ).abs_diff( range.0 )
+            + u32::from( candidate.end() ).abs_diff( range.1 )
+    };
+    candidates.sort_by_key( |candidate| ( distance( candidate ), candidate.len() ) );
+
+    let mut snapped: Vec<(u32, u32)> = vec![];
+    for candidate in candidates {
+        let candidate: (u32, u32) = ( candidate.start().into(), candidate.end().into() );
+        if candidate != *range && !snapped.contains( &candidate ) {
+            snapped.push( candidate );
+        }
+    }
+    snapped
+}
+
+/// Checks if the new function refers to an unqualified `ControlFlow`, and if
+/// so, adds a `use` for it to the module containing the function. Uses
+/// `insert_use`, so the import lands after any inner attributes and alongside
+/// the existing imports. Nothing is added if the module already imports
+/// `ControlFlow`. `no_std` crates get `core::ops::ControlFlow`.
+/// Returns the `TextEdit` that adds the import (which is empty if no import is
+/// needed)
+fn fixup_controlflow(
+    text: &str,
+    callee: &ast::Fn,
+    insert_use_config: &InsertUseConfig,
+    no_std: bool,
+) -> TextEdit {
+    let uses_controlflow: bool = callee
+        .syntax()
+        .descendants()
+        .filter_map( ast::Path::cast )
+        .any( |path| path.qualifier().is_none() && is_controlflow( &path ) );
+    if !uses_controlflow {
+        return TextEdit::default();
+    }
+
+    // The closest enclosing module, or the file itself
+    let Some( scope ) = callee.syntax().ancestors().find_map( |node| {
+        match ast::Module::cast( node.clone() ) {
+            Some( module ) => module.item_list().map( ImportScope::Module ),
+            None => ast::SourceFile::cast( node ).map( ImportScope::File ),
+        }
+    } ) else {
+        return TextEdit::default();
+    };
+
+    let already_imported: bool = scope
+        .as_syntax_node()
+        .children()
+        .filter_map( ast::Use::cast )
+        .any( |use_item| {
+            use_item
+                .syntax()
+                .descendants()
+                .filter_map( ast::NameRef::cast )
+                .any( |name_ref| name_ref.text().as_str() == "ControlFlow" )
+        } );
+    if already_imported {
+        return TextEdit::default();
+    }
+
+    let import: ast::Path = make::path_from_text(
+        if no_std { "core::ops::ControlFlow" } else { "std::ops::ControlFlow" }
+    );
+    let scope: ImportScope = scope.clone_for_update();
+    insert_use( &scope, import, insert_use_config );
+
+    let new_text: String = match scope.as_syntax_node().ancestors().last() {
+        Some( root ) => root.to_string(),
+        None => return TextEdit::default(),
+    };
+    text_edit_from_diff( text, &new_text )
+}
+
+/// Checks if the last segment of a path is `ControlFlow`
+fn is_controlflow( path: &ast::Path ) -> bool {
+    path.segment()
+        .and_then( |segment| segment.name_ref() )
+        .map_or( false, |name_ref| name_ref.text().as_str() == "ControlFlow" )
+}
+
+/// Builds a `TextEdit` that turns `old` into `new`, by replacing everything
+/// between their common prefix and suffix.
+fn text_edit_from_diff( old: &str, new: &str ) -> TextEdit {
+    let prefix: usize = old
+        .char_indices()
+        .zip( new.chars() )
+        .find( |((_, a), b)| a != b )
+        .map_or( old.len().min( new.len() ), |((idx, _), _)| idx );
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip( new[prefix..].chars().rev() )
+        .take_while( |(a, b)| a == b )
+        .map( |(a, _)| a.len_utf8() )
+        .sum();
+
+    if prefix == old.len() && prefix == new.len() {
+        return TextEdit::default();
+    }
+
+    TextEdit::replace(
+        TextRange::new(
+            TextSize::new( prefix as u32 ),
+            TextSize::new( ( old.len() - suffix ) as u32 ),
+        ),
+        new[prefix..new.len() - suffix].to_string(),
+    )
+}
+
+/// The edition of the crate that owns the given file, as declared in its
+/// `Cargo.toml`. Files outside the crate graph use the current edition.
+pub fn file_edition(
+    sema: &Semantics<'_, RootDatabase>,
+    file_id: FileId,
+) -> Edition {
+    sema.file_to_module_def( file_id )
+        .map_or( Edition::CURRENT, |module| module.krate().edition( sema.db ) )
+}
+
+/// Checks if the crate that owns the given file is `#![no_std]`
+pub fn crate_is_no_std(
+    sema: &Semantics<'_, RootDatabase>,
+    file_id: FileId,
+) -> bool {
+    let Some( module ) = sema.file_to_module_def( file_id ) else {
+        return false;
+    };
+    let root_file: EditionedFileId = module
+        .krate()
+        .root_module()
+        .definition_source_file_id( sema.db )
+        .original_file( sema.db );
+    sema.parse( root_file )
+        .attrs()
+        .any( |attr| attr.simple_name().map_or( false, |name| name == "no_std" ) )
 }
 
 #[cfg(test)]
@@ -724,4 +1568,103 @@ mod tests {
         assert_eq!(left_path, right_path);
 
     }
+
+    #[test]
+    fn test_inserted_ranges_account_for_earlier_indels() {
+        let mut builder = TextEdit::builder();
+        builder.replace( TextRange::new( 0.into(), 3.into() ), "a".to_string() );
+        builder.insert( 10.into(), "fn foo() {}".to_string() );
+        let edit: TextEdit = builder.finish();
+
+        let ranges: Vec<TextRange> = inserted_ranges( &edit );
+        assert_eq!( ranges[0], TextRange::at( 0.into(), 1.into() ) );
+        assert_eq!( ranges[1], TextRange::at( 8.into(), 11.into() ) );
+    }
+
+    #[test]
+    fn test_map_range_rejects_deleted_offsets() {
+        let edit: TextEdit = TextEdit::replace(
+            TextRange::new( 4.into(), 8.into() ),
+            "x".to_string(),
+        );
+
+        // Entirely after the edit, so it is shifted
+        assert_eq!(
+            map_range( TextRange::new( 10.into(), 12.into() ), &edit ),
+            Some( TextRange::new( 7.into(), 9.into() ) )
+        );
+        // Starts inside the deleted text, so it can't be mapped
+        assert_eq!( map_range( TextRange::new( 5.into(), 12.into() ), &edit ), None );
+    }
+
+    #[test]
+    fn test_text_edit_from_diff() {
+        let old: &str = "fn main() {}\n";
+        let new: &str = "use a::b;\nfn main() {}\n";
+        let edit: TextEdit = text_edit_from_diff( old, new );
+
+        let mut text: String = old.to_string();
+        edit.apply( &mut text );
+        assert_eq!( text, new );
+        assert!( text_edit_from_diff( old, old ).is_empty() );
+    }
+
+    #[test]
+    fn test_snap_candidates_grow_to_whole_statements() {
+        let text: &str = "fn main() {\n    let a = 1;\n    let b = a + 2;\n}\n";
+        let source_file: SourceFile = SourceFile::parse( text, Edition::CURRENT ).tree();
+        let start: u32 = text.find( "= 1;" ).unwrap() as u32;
+        let end: u32 = text.find( " = a" ).unwrap() as u32;
+
+        let candidates: Vec<(u32, u32)> = snap_candidates( &source_file, &(start, end) );
+        let stmts_start: u32 = text.find( "let a" ).unwrap() as u32;
+        let stmts_end: u32 = ( text.find( "+ 2;" ).unwrap() + "+ 2;".len() ) as u32;
+        assert_eq!( candidates.first(), Some( &(stmts_start, stmts_end) ) );
+        assert!( !candidates.contains( &(start, end) ) );
+    }
+
+    #[test]
+    fn test_fixup_controlflow_after_inner_attributes() {
+        let text: &str = "#![allow(unused)]\n\nfn fun_name() -> ControlFlow<()> {\n    ControlFlow::Continue(())\n}\n";
+        let source_file: SourceFile = SourceFile::parse( text, Edition::CURRENT ).tree();
+        let callee: ast::Fn = source_file.syntax().descendants().find_map( ast::Fn::cast ).unwrap();
+
+        let edit: TextEdit = fixup_controlflow(
+            text,
+            &callee,
+            &generate_assist_config().insert_use,
+            false,
+        );
+        let mut fixed: String = text.to_string();
+        edit.apply( &mut fixed );
+
+        let attr_idx: usize = fixed.find( "#![allow(unused)]" ).unwrap();
+        let use_idx: usize = fixed.find( "use std::ops::ControlFlow;" ).unwrap();
+        assert!( attr_idx < use_idx );
+
+        // Running it again doesn't duplicate the import
+        let source_file: SourceFile = SourceFile::parse( &fixed, Edition::CURRENT ).tree();
+        let callee: ast::Fn = source_file.syntax().descendants().find_map( ast::Fn::cast ).unwrap();
+        assert!( fixup_controlflow( &fixed, &callee, &generate_assist_config().insert_use, false ).is_empty() );
+    }
+
+    #[test]
+    fn test_fixup_controlflow_no_std_nested_module() {
+        let text: &str = "mod inner {\n    fn fun_name() -> ControlFlow<()> {\n        ControlFlow::Continue(())\n    }\n}\n";
+        let source_file: SourceFile = SourceFile::parse( text, Edition::CURRENT ).tree();
+        let callee: ast::Fn = source_file.syntax().descendants().find_map( ast::Fn::cast ).unwrap();
+
+        let edit: TextEdit = fixup_controlflow(
+            text,
+            &callee,
+            &generate_assist_config().insert_use,
+            true,
+        );
+        let mut fixed: String = text.to_string();
+        edit.apply( &mut fixed );
+
+        // The import goes inside the module, not at the top of the file
+        assert!( fixed.starts_with( "mod inner {" ) );
+        assert!( fixed.contains( "use core::ops::ControlFlow;" ) );
+    }
 }
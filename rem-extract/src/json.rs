@@ -0,0 +1,130 @@
+//! JSON output, for tools that shell out to `rem-extract` rather than linking
+//! against it.
+
+use std::time::Duration;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use ra_ap_ide::{
+    TextEdit,
+    TextRange,
+};
+
+use crate::{
+    error::ExtractionError,
+    extraction::{
+        ExtractionOutput,
+        FunctionInfo,
+        ParamInfo,
+        PassingMode,
+        Timings,
+    },
+};
+
+/// The JSON object for a successful extraction
+pub fn output_to_json( output: &ExtractionOutput ) -> Value {
+    json!( {
+        "status": "ok",
+        "code": output.code,
+        "file_path": output.file_path,
+        "range": range_to_json( output.range ),
+        "caller": function_to_json( &output.caller ),
+        "callee": function_to_json( &output.callee ),
+        "params": output.params.iter().map( param_to_json ).collect::<Vec<Value>>(),
+        "ret_type": output.ret_type,
+        "edits": output.edits.iter().map( edit_to_json ).collect::<Vec<Value>>(),
+        "timings": timings_to_json( &output.timings ),
+        "missing_out_dirs": output.missing_out_dirs,
+    } )
+}
+
+/// The JSON object for a failed extraction. `elapsed` is how long the caller
+/// spent before the error.
+pub fn error_to_json( error: &ExtractionError, elapsed: Duration ) -> Value {
+    json!( {
+        "status": "error",
+        "error": error_details( error ),
+        "timings": {
+            "total_ms": millis( elapsed ),
+        },
+    } )
+}
+
+/// The kind, exit code, message and range of an error
+pub fn error_details( error: &ExtractionError ) -> Value {
+    json!( {
+        "kind": error.kind(),
+        "exit_code": error.exit_code(),
+        "message": error.to_string(),
+        "range": error.range().map( range_to_json ),
+    } )
+}
+
+pub fn range_to_json( range: TextRange ) -> Value {
+    json!( {
+        "start": u32::from( range.start() ),
+        "end": u32::from( range.end() ),
+    } )
+}
+
+fn function_to_json( function: &FunctionInfo ) -> Value {
+    json!( {
+        "name": function.name,
+        "span": range_to_json( function.range ),
+    } )
+}
+
+fn param_to_json( param: &ParamInfo ) -> Value {
+    json!( {
+        "name": param.name,
+        "type": param.ty,
+        "mode": match param.mode {
+            PassingMode::Value => "value",
+            PassingMode::Ref => "ref",
+            PassingMode::MutRef => "mut_ref",
+        },
+    } )
+}
+
+/// Each edit is a list of indels, in the coordinates of the text before that
+/// edit was applied
+fn edit_to_json( edit: &TextEdit ) -> Value {
+    Value::Array(
+        edit.iter()
+            .map( |indel| json!( {
+                "delete": range_to_json( indel.delete ),
+                "insert": indel.insert,
+            } ) )
+            .collect()
+    )
+}
+
+pub fn timings_to_json( timings: &Timings ) -> Value {
+    let mut phases: serde_json::Map<String, Value> = timings.phases
+        .iter()
+        .map( |(phase, duration)| ( format!( "{}_ms", phase ), json!( millis( *duration ) ) ) )
+        .collect();
+    phases.insert( "total_ms".to_string(), json!( millis( timings.total() ) ) );
+    Value::Object( phases )
+}
+
+fn millis( duration: Duration ) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_to_json() {
+        let value: Value = error_to_json( &ExtractionError::SameIdx, Duration::from_millis( 5 ) );
+        assert_eq!( value["status"], "error" );
+        assert_eq!( value["error"]["kind"], "SameIdx" );
+        assert_eq!( value["error"]["message"], ExtractionError::SameIdx.to_string() );
+        assert!( value["error"]["range"].is_null() );
+    }
+}
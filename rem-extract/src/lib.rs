@@ -1,6 +1,22 @@
 pub mod extraction;
 pub mod error;
+pub mod session;
+pub mod diagnosis;
+pub mod selection;
+pub mod write;
+pub mod patch;
+pub mod json;
+pub mod render;
+pub mod batch;
+pub mod server;
+pub mod lsp;
+pub mod config;
 
 mod extract_tests;
 mod test_details;
-mod extraction_utils;
\ No newline at end of file
+mod extraction_utils;
+
+/// The fixture harness behind `rem-extract test`. It is only public so the
+/// binary can run it, and is not part of the API.
+#[doc(hidden)]
+pub use extract_tests::run_fixture_tests;
@@ -0,0 +1,369 @@
+//! A small Language Server that offers extract method as a code action, for
+//! editors that don't expose rust-analyzer's assists the way REM needs.
+//!
+//! The server keeps the text of every open document, and uses it instead of
+//! the file on disk. `textDocument/codeAction` offers an "Extract method (REM)"
+//! action when the selection can be extracted. The action runs the
+//! `rem.extractMethod` command, whose argument is
+//! `{ uri, range, name }`. Clients are expected to prompt for `name` before
+//! executing it; without one the name generated by rust-analyzer is kept.
+//! The command sends the result of the extraction (including the rename and
+//! import fixups) to the client as a `workspace/applyEdit`.
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    error::Error,
+    fs,
+    path::PathBuf,
+};
+
+use lsp_server::{
+    Connection,
+    ErrorCode,
+    Message,
+    Notification,
+    Request,
+    RequestId,
+    Response,
+};
+
+use lsp_types::{
+    self as lsp,
+    notification::{
+        DidChangeTextDocument,
+        DidCloseTextDocument,
+        DidOpenTextDocument,
+        Notification as _,
+    },
+    request::{
+        ApplyWorkspaceEdit,
+        CodeActionRequest,
+        ExecuteCommand,
+        Request as _,
+    },
+    Url,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use ra_ap_ide::TextSize;
+
+use ra_ap_ide_db::line_index::{
+    LineIndex,
+    WideEncoding,
+    WideLineCol,
+};
+
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+};
+
+use crate::{
+    config::LoadOptions,
+    error::ExtractionError,
+    extraction::{
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    extraction_utils::convert_to_abs_path_buf,
+    patch::combine_edits,
+    selection::{
+        ColumnUnit,
+        Position,
+        Selection,
+    },
+    session::{
+        ExtractionSession,
+        SessionCache,
+    },
+};
+
+pub const EXTRACT_COMMAND: &str = "rem.extractMethod";
+const EXTRACT_TITLE: &str = "Extract method (REM)";
+
+type LspResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Runs the server over stdin/stdout until the client shuts it down. Every
+/// workspace is loaded with `options`.
+pub fn run_lsp_server( options: LoadOptions ) -> LspResult<()> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities: lsp::ServerCapabilities = lsp::ServerCapabilities {
+        text_document_sync: Some( lsp::TextDocumentSyncCapability::Kind( lsp::TextDocumentSyncKind::FULL ) ),
+        code_action_provider: Some( lsp::CodeActionProviderCapability::Options( lsp::CodeActionOptions {
+            code_action_kinds: Some( vec![ lsp::CodeActionKind::REFACTOR_EXTRACT ] ),
+            ..Default::default()
+        } ) ),
+        execute_command_provider: Some( lsp::ExecuteCommandOptions {
+            commands: vec![ EXTRACT_COMMAND.to_string() ],
+            ..Default::default()
+        } ),
+        ..Default::default()
+    };
+    connection.initialize( json!( {
+        "capabilities": capabilities,
+        "serverInfo": {
+            "name": "rem-extract",
+            "version": env!( "CARGO_PKG_VERSION" ),
+        },
+    } ) )?;
+
+    let mut server: LspServer = LspServer::new( connection, options );
+    server.run()?;
+    drop( server );
+    io_threads.join()?;
+    Ok( () )
+}
+
+struct LspServer {
+    connection: Connection,
+    sessions: SessionCache,
+    /// The text of each open document
+    documents: HashMap<Url, String>,
+    /// Documents whose text hasn't been pushed to their session yet
+    dirty: HashSet<Url>,
+    next_request_id: i32,
+}
+
+impl LspServer {
+    fn new( connection: Connection, options: LoadOptions ) -> Self {
+        LspServer {
+            connection,
+            sessions: SessionCache::with_options( options ),
+            documents: HashMap::new(),
+            dirty: HashSet::new(),
+            next_request_id: 0,
+        }
+    }
+
+    fn run( &mut self ) -> LspResult<()> {
+        while let Ok( message ) = self.connection.receiver.recv() {
+            match message {
+                Message::Request( request ) => {
+                    if self.connection.handle_shutdown( &request )? {
+                        return Ok( () );
+                    }
+                    let response: Response = self.handle_request( request );
+                    self.connection.sender.send( Message::Response( response ) )?;
+                },
+                Message::Notification( notification ) => self.handle_notification( notification ),
+                // The only requests we send are `workspace/applyEdit`, and
+                // there is nothing to do if the client refuses one
+                Message::Response( _ ) => (),
+            }
+        }
+        Ok( () )
+    }
+
+    fn handle_request( &mut self, request: Request ) -> Response {
+        let id: RequestId = request.id.clone();
+        let result: Result<Value, ExtractionError> = match request.method.as_str() {
+            CodeActionRequest::METHOD => match serde_json::from_value( request.params ) {
+                Ok( params ) => Ok( self.code_actions( params ) ),
+                Err( e ) => return invalid_params( id, e ),
+            },
+            ExecuteCommand::METHOD => match serde_json::from_value( request.params ) {
+                Ok( params ) => self.execute_command( params ),
+                Err( e ) => return invalid_params( id, e ),
+            },
+            method => return Response::new_err(
+                id,
+                ErrorCode::MethodNotFound as i32,
+                format!( "unknown method `{}`", method ),
+            ),
+        };
+        match result {
+            Ok( result ) => Response::new_ok( id, result ),
+            Err( e ) => Response::new_err( id, ErrorCode::RequestFailed as i32, e.to_string() ),
+        }
+    }
+
+    fn handle_notification( &mut self, notification: Notification ) {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                if let Ok( params ) = serde_json::from_value::<lsp::DidOpenTextDocumentParams>( notification.params ) {
+                    self.set_document( params.text_document.uri, Some( params.text_document.text ) );
+                }
+            },
+            DidChangeTextDocument::METHOD => {
+                if let Ok( params ) = serde_json::from_value::<lsp::DidChangeTextDocumentParams>( notification.params ) {
+                    // With full sync, the last change holds the whole document
+                    if let Some( change ) = params.content_changes.into_iter().last() {
+                        self.set_document( params.text_document.uri, Some( change.text ) );
+                    }
+                }
+            },
+            DidCloseTextDocument::METHOD => {
+                if let Ok( params ) = serde_json::from_value::<lsp::DidCloseTextDocumentParams>( notification.params ) {
+                    self.set_document( params.text_document.uri, None );
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Records the text of a document, or that it was closed and the file on
+    /// disk applies again
+    fn set_document( &mut self, uri: Url, text: Option<String> ) {
+        match text {
+            Some( text ) => {
+                self.documents.insert( uri.clone(), text );
+            },
+            None => {
+                self.documents.remove( &uri );
+            },
+        }
+        self.dirty.insert( uri );
+    }
+
+    /// Offers the extract method action if the range can be extracted
+    fn code_actions( &mut self, params: lsp::CodeActionParams ) -> Value {
+        let uri: Url = params.text_document.uri;
+        let input: ExtractionInput = match self.input_for( &uri, params.range, "" ) {
+            Ok( input ) => input,
+            Err( _ ) => return json!( [] ),
+        };
+        let applicable: bool = self
+            .session_for( &input.file_path )
+            .is_ok_and( |session| session.check( &input ).is_ok() );
+        if !applicable {
+            return json!( [] );
+        }
+
+        let action: lsp::CodeAction = lsp::CodeAction {
+            title: EXTRACT_TITLE.to_string(),
+            kind: Some( lsp::CodeActionKind::REFACTOR_EXTRACT ),
+            command: Some( lsp::Command {
+                title: EXTRACT_TITLE.to_string(),
+                command: EXTRACT_COMMAND.to_string(),
+                arguments: Some( vec![ json!( {
+                    "uri": uri,
+                    "range": params.range,
+                    "name": Value::Null,
+                } ) ] ),
+            } ),
+            ..Default::default()
+        };
+        json!( [ action ] )
+    }
+
+    /// Runs an extraction, and asks the client to apply it
+    fn execute_command( &mut self, params: lsp::ExecuteCommandParams ) -> Result<Value, ExtractionError> {
+        if params.command != EXTRACT_COMMAND {
+            return Err( ExtractionError::InvalidRequest( format!( "unknown command `{}`", params.command ) ) );
+        }
+        let argument: &Value = params.arguments
+            .first()
+            .ok_or_else( || ExtractionError::InvalidRequest( "missing the command argument".to_string() ) )?;
+        let uri: Url = serde_json::from_value( argument["uri"].clone() )
+            .map_err( |e| ExtractionError::InvalidRequest( format!( "`uri`: {}", e ) ) )?;
+        let range: lsp::Range = serde_json::from_value( argument["range"].clone() )
+            .map_err( |e| ExtractionError::InvalidRequest( format!( "`range`: {}", e ) ) )?;
+        let name: &str = argument["name"].as_str().unwrap_or( "" );
+
+        let input: ExtractionInput = self.input_for( &uri, range, name )?;
+        let output: ExtractionOutput = self.session_for( &input.file_path )?.extract( &input )?;
+
+        // Keep the document in step with the session until the client sends
+        // the edited text back
+        self.documents.insert( uri.clone(), output.code.clone() );
+
+        // LSP edits are all relative to the document before any of them, while
+        // each of the extraction's edits applies to the text after the last
+        let line_index: LineIndex = LineIndex::new( &output.original_code );
+        let edits: Vec<lsp::TextEdit> = combine_edits( &output.original_code, &output.edits )
+            .iter()
+            .map( |indel| lsp::TextEdit {
+                range: lsp::Range {
+                    start: lsp_position( &line_index, indel.delete.start() ),
+                    end: lsp_position( &line_index, indel.delete.end() ),
+                },
+                new_text: indel.insert.clone(),
+            } )
+            .collect();
+        let edit: lsp::WorkspaceEdit = lsp::WorkspaceEdit {
+            changes: Some( HashMap::from( [ ( uri, edits ) ] ) ),
+            ..Default::default()
+        };
+        let apply: lsp::ApplyWorkspaceEditParams = lsp::ApplyWorkspaceEditParams {
+            label: Some( format!( "Extract method `{}`", output.callee.name ) ),
+            edit,
+        };
+        self.next_request_id += 1;
+        let request: Request = Request::new(
+            RequestId::from( self.next_request_id ),
+            ApplyWorkspaceEdit::METHOD.to_string(),
+            apply,
+        );
+        // The client has gone away if this fails, and the main loop will stop
+        let _ = self.connection.sender.send( Message::Request( request ) );
+        Ok( Value::Null )
+    }
+
+    /// Builds the input for an LSP range, which counts UTF-16 code units from 0
+    fn input_for( &self, uri: &Url, range: lsp::Range, name: &str ) -> Result<ExtractionInput, ExtractionError> {
+        let path: PathBuf = uri
+            .to_file_path()
+            .map_err( |_| ExtractionError::InvalidPath( uri.to_string() ) )?;
+        let mut input: ExtractionInput = ExtractionInput::new( &path.to_string_lossy(), name, 0, 0 );
+        input.selection = Some( Selection::LineCol {
+            start: Position { line: range.start.line + 1, col: range.start.character + 1 },
+            end: Position { line: range.end.line + 1, col: range.end.character + 1 },
+            unit: ColumnUnit::Utf16,
+        } );
+        Ok( input )
+    }
+
+    /// The session for a file, with the text of any changed documents in its
+    /// workspace pushed to it first
+    fn session_for( &mut self, file: &str ) -> Result<&mut ExtractionSession, ExtractionError> {
+        let workspace: PathBuf = self.sessions.workspace_of( file )?;
+        // A new session is loaded from disk, so every open document is pushed
+        if !self.sessions.contains( &workspace ) {
+            self.dirty.extend( self.documents.keys().cloned() );
+        }
+        let (_, session) = self.sessions.get_or_load( file )?;
+
+        let pushed: Vec<Url> = self.dirty
+            .iter()
+            .filter_map( |uri| {
+                let path: PathBuf = uri.to_file_path().ok()?;
+                if !path.starts_with( &workspace ) {
+                    return None;
+                }
+                let abs_path: AbsPathBuf = convert_to_abs_path_buf( &path.to_string_lossy() ).ok()?;
+                let text: String = match self.documents.get( uri ) {
+                    Some( text ) => text.clone(),
+                    None => fs::read_to_string( &path ).ok()?,
+                };
+                // Files outside the crate graph are left alone
+                let file_id: FileId = session.file_id( &abs_path ).ok()?;
+                session.set_file_text( file_id, text );
+                Some( uri.clone() )
+            } )
+            .collect();
+        for uri in pushed {
+            self.dirty.remove( &uri );
+        }
+        Ok( session )
+    }
+}
+
+/// Converts an offset to an LSP position, which counts UTF-16 code units
+fn lsp_position( line_index: &LineIndex, offset: TextSize ) -> lsp::Position {
+    let wide: WideLineCol = line_index
+        .to_wide( WideEncoding::Utf16, line_index.line_col( offset ) )
+        .unwrap_or( WideLineCol { line: 0, col: 0 } );
+    lsp::Position { line: wide.line, character: wide.col }
+}
+
+fn invalid_params( id: RequestId, error: serde_json::Error ) -> Response {
+    Response::new_err( id, ErrorCode::InvalidParams as i32, error.to_string() )
+}
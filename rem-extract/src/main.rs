@@ -1,11 +1,29 @@
 mod logging;
 mod messages;
-mod extraction_utils;
 
-mod extraction;
-use extraction::{
-    extract_method,
-    ExtractionInput
+use rem_extract::{
+    error,
+    extraction::{
+        extract_input_from_source,
+        extract_method_with_options,
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    run_fixture_tests,
+    selection::Selection,
+    write::write_in_place,
+    patch::unified_diff,
+    json::{
+        error_to_json,
+        output_to_json,
+    },
+    render::render_error,
+    batch::{
+        run_batch,
+        BatchSummary,
+    },
+    server::serve,
+    lsp::run_lsp_server,
 };
 
 use log::{
@@ -16,18 +34,25 @@ use log::{
 mod args;
 use args::{
     EXTRACTArgs,
-    EXTRACTCommands
+    EXTRACTCommands,
+    OutputFormat,
 };
 
-mod extract_tests;
-mod test_details;
-use extract_tests::{
-    test,
-    test_verbose,
-    test_spammy,
+use std::{
+    fs,
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Write,
+    },
+    process,
+    time::Instant,
 };
 
-mod error;
+use rem_extract::error::exit_code;
+use ra_ap_ide::TextRange;
+use ra_ap_syntax::SourceFile;
 
 use clap::Parser;
 
@@ -44,39 +69,212 @@ fn main() {
             new_fn_name,
             start_index,
             end_index,
+            range,
+            unit,
+            markers,
+            item,
+            stmts,
+            name_fallback,
+            snap,
+            stdin,
+            edition,
+            in_place,
+            backup,
+            diff,
+            color,
+            format,
             verbose,
+            cargo,
         } => {
             info!("Running 'run' subcommand");
             info!("File Path: {:?}", file_path);
             info!("New Function Name: {}", new_fn_name);
-            info!("Start Index: {}", start_index);
-            info!("End Index: {}", end_index);
+            info!("Start Index: {:?}", start_index);
+            info!("End Index: {:?}", end_index);
+            info!("Range: {:?} ({:?})", range, unit);
+            info!("Markers: {:?}", markers);
+            info!("Item: {:?} ({:?})", item, stmts);
             info!("Verbose: {}", if *verbose { "yes" } else { "no" });
 
-            let input = ExtractionInput::new(
+            let mut input = ExtractionInput::new(
                 file_path.to_str().unwrap(),
                 new_fn_name,
-                *start_index as u32,
-                *end_index as u32,
+                start_index.unwrap_or(0) as u32,
+                end_index.unwrap_or(0) as u32,
             );
+            if let Some(range) = range {
+                match Selection::parse_line_col(range, *unit) {
+                    Ok(selection) => input.selection = Some(selection),
+                    Err(e) => {
+                        eprintln!("error: invalid --range: {}", e);
+                        process::exit(exit_code::BAD_INPUT);
+                    }
+                }
+            }
+            if let Some(name) = markers {
+                input.selection = Some(Selection::Markers {
+                    name: if name.is_empty() { None } else { Some(name.clone()) },
+                });
+            }
+            if let (Some(path), Some(target)) = (item, stmts) {
+                input.selection = Some(Selection::Item {
+                    path: path.clone(),
+                    target: target.clone(),
+                });
+            }
+            input.allow_name_fallback = *name_fallback;
+            input.snap = *snap;
+
+            // Kept for the error report, as the input is consumed
+            let report_path: String = input.file_path.clone();
+            let report_selection: Option<TextRange> = match input.selection {
+                None if input.start_idx < input.end_idx => Some(TextRange::new(input.start_idx.into(), input.end_idx.into())),
+                _ => None,
+            };
 
-            let extraction_output: Result<(String, String), error::ExtractionError> = extract_method(input);
-            let (output_code, _caller_method) = match extraction_output {
-                Ok((output_code, caller_method)) => {
-                    info!("Output Code: {}", output_code);
-                    info!("Caller Method: {}", caller_method);
-                    (output_code, caller_method)
+            // Standalone source is read up front, so it can be shown in errors
+            let source: Option<String> = if *stdin {
+                match io::read_to_string(io::stdin()) {
+                    Ok(source) => Some(source),
+                    Err(e) => {
+                        eprintln!("Could not read the source from stdin: {}", e);
+                        process::exit(exit_code::IO);
+                    }
+                }
+            } else {
+                None
+            };
+
+            if *verbose {
+                eprintln!("Extracting from {}", report_path);
+            }
+            let start_time: Instant = Instant::now();
+            let extracted: Result<ExtractionOutput, error::ExtractionError> = match &source {
+                Some(source) => extract_input_from_source(source, input, *edition),
+                None => extract_method_with_options(input, &cargo.load_options()),
+            };
+            let extraction_output: Result<ExtractionOutput, error::ExtractionError> = extracted
+                .and_then(|output| {
+                    if *in_place {
+                        write_in_place(&output, *backup)?;
+                        info!("Wrote {}", output.file_path);
+                    }
+                    Ok(output)
+                });
+            let output: ExtractionOutput = match extraction_output {
+                Ok(output) => {
+                    info!("Output Code: {}", output.code);
+                    info!("Caller Method: {}", output.caller.name);
+                    info!("Callee Method: {}", output.callee.name);
+                    info!("Extracted Range: {:?}", output.range);
+                    info!("Timings: {:?}", output.timings);
+                    output
                 },
                 Err(e) => {
                     info!("Error: {}", e);
-                    return;
+                    match format {
+                        OutputFormat::Json => println!("{}", error_to_json(&e, start_time.elapsed())),
+                        OutputFormat::Text => {
+                            let source_file: Option<SourceFile> = source
+                                .clone()
+                                .or_else(|| fs::read_to_string(&report_path).ok())
+                                .map(|text| SourceFile::parse(&text, *edition).tree());
+                            eprint!("{}", render_error(&e, &report_path, source_file.as_ref(), report_selection));
+                        }
+                    }
+                    if *verbose {
+                        eprintln!("Failed after {:.2?}", start_time.elapsed());
+                    }
+                    process::exit(e.exit_code());
                 }
             };
 
-            println!("{}", output_code);
+            if *verbose {
+                print_phases(&output);
+            }
+            if *format == OutputFormat::Text && !output.missing_out_dirs.is_empty() {
+                eprintln!(
+                    "warning: build scripts were not run, so OUT_DIR is unknown for: {}. Use --trusted to run them",
+                    output.missing_out_dirs.join(", ")
+                );
+            }
+            if *format == OutputFormat::Json {
+                println!("{}", output_to_json(&output));
+                return;
+            }
+            if *diff {
+                if *color {
+                    colored::control::set_override(true);
+                }
+                print!("{}", unified_diff(&output.original_code, &output.edits, &output.relative_path, *color));
+                // Nothing else on stdout, so it can be piped into `git apply`
+                return;
+            } else if !*in_place {
+                println!("{}", output.code);
+            }
             println!("Extraction Successful");
         }
 
+        EXTRACTCommands::Batch {
+            requests,
+            output,
+            in_place,
+            cargo,
+        } => {
+            info!("Running 'batch' subcommand");
+            let reader: Box<dyn BufRead> = if requests == "-" {
+                Box::new(io::stdin().lock())
+            } else {
+                match fs::File::open(requests) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        eprintln!("Could not open {}: {}", requests, e);
+                        process::exit(exit_code::IO);
+                    }
+                }
+            };
+            let mut writer: Box<dyn Write> = match output {
+                Some(path) => match fs::File::create(path) {
+                    Ok(file) => Box::new(file),
+                    Err(e) => {
+                        eprintln!("Could not create {}: {}", path, e);
+                        process::exit(exit_code::IO);
+                    }
+                },
+                None => Box::new(io::stdout().lock()),
+            };
+
+            match run_batch(reader, &mut writer, *in_place, &cargo.load_options()) {
+                Ok(summary) => {
+                    let BatchSummary { succeeded, failed, exit_code } = summary;
+                    eprintln!("{} succeeded, {} failed", succeeded, failed);
+                    if let Some(code) = exit_code {
+                        process::exit(code);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Batch failed: {}", e);
+                    process::exit(exit_code::IO);
+                }
+            }
+        }
+
+        EXTRACTCommands::Serve { cargo } => {
+            info!("Running 'serve' subcommand");
+            if let Err(e) = serve(io::stdin().lock(), &mut io::stdout().lock(), cargo.load_options()) {
+                eprintln!("Server failed: {}", e);
+                process::exit(exit_code::IO);
+            }
+        }
+
+        EXTRACTCommands::Lsp { cargo } => {
+            info!("Running 'lsp' subcommand");
+            if let Err(e) = run_lsp_server(cargo.load_options()) {
+                eprintln!("Language server failed: {}", e);
+                process::exit(exit_code::INTERNAL);
+            }
+        }
+
         EXTRACTCommands::Test {
             verbose,
             spammy
@@ -84,16 +282,18 @@ fn main() {
             if verbose.clone() || spammy.clone() {assert_ne!(verbose.clone(), spammy.clone(), "Verbose and Spammy cannot be run at the same time");}
             info!("Running 'test' subcommand");
             info!("Verbose: {}", if *verbose { "yes" } else { "no" });
-            if *verbose {
-                test_verbose();
-            } else if *spammy {
-                test_spammy();
-            }else {
-                test();
-            }
+            run_fixture_tests(*verbose, *spammy);
 
         }
 
     }
 }
 
+/// Prints each phase of the extraction and how long it took, for `--verbose`
+fn print_phases(output: &ExtractionOutput) {
+    eprintln!("Extracted {:?} from {} into {}", output.range, output.caller.name, output.callee.name);
+    for (phase, duration) in &output.timings.phases {
+        eprintln!("  {:<8} {:>10.2?}", phase, duration);
+    }
+    eprintln!("  {:<8} {:>10.2?}", "total", output.timings.total());
+}
@@ -0,0 +1,369 @@
+//! Unified diff output, so an extraction can be reviewed or fed to `git apply`
+//! instead of printing the whole file.
+
+use std::ops::Range;
+
+use colored::*;
+use ra_ap_ide::{
+    TextEdit,
+    TextRange,
+    TextSize,
+};
+
+/// Lines of unchanged context around each hunk
+const CONTEXT: usize = 3;
+
+const NO_NEWLINE: &str = "\\ No newline at end of file";
+
+/// A line of the diff, with its 0-based line numbers in the old and new text
+struct DiffLine<'a> {
+    tag: char,
+    text: &'a str,
+    old_idx: usize,
+    new_idx: usize,
+}
+
+/// A piece of the edited text: either text kept from the original, by its
+/// range there, or text inserted by an edit
+enum Segment {
+    Original( Range<usize> ),
+    Inserted( String ),
+}
+
+impl Segment {
+    fn len( &self ) -> usize {
+        match self {
+            Segment::Original( range ) => range.len(),
+            Segment::Inserted( text ) => text.len(),
+        }
+    }
+
+    /// The part of the segment from `start` to `end`, relative to its start
+    fn slice( &self, start: usize, end: usize ) -> Segment {
+        match self {
+            Segment::Original( range ) => Segment::Original( range.start + start..range.start + end ),
+            Segment::Inserted( text ) => Segment::Inserted( text[start..end].to_string() ),
+        }
+    }
+}
+
+/// Generates a unified diff (with `a/` and `b/` prefixed headers) for the
+/// `edits` applied in order to the `original` text of the file at `path`.
+/// `path` should be relative to the root the patch is applied from. Returns an
+/// empty string if nothing changed.
+/// If `color` is set, removed lines are red, added lines green and hunk headers
+/// cyan.
+pub fn unified_diff(
+    original: &str,
+    edits: &[TextEdit],
+    path: &str,
+    color: bool,
+) -> String {
+    let (segments, modified): (Vec<Segment>, String) = compose_edits( original, edits );
+    let lines: Vec<DiffLine> = edit_lines( original, &modified, &segments );
+    let changes: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter( |(_, line)| line.tag != ' ' )
+        .map( |(idx, _)| idx )
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    let old_len: usize = line_ranges( original ).len();
+    let new_len: usize = line_ranges( &modified ).len();
+
+    let mut out: Vec<String> = vec![
+        paint( &format!( "--- a/{}", path ), color, |s| s.bold() ),
+        paint( &format!( "+++ b/{}", path ), color, |s| s.bold() ),
+    ];
+
+    for (first, last) in group_hunks( &changes ) {
+        let start: usize = first.saturating_sub( CONTEXT );
+        let end: usize = ( last + CONTEXT + 1 ).min( lines.len() );
+        let hunk: &[DiffLine] = &lines[start..end];
+
+        let old_count: usize = hunk.iter().filter( |line| line.tag != '+' ).count();
+        let new_count: usize = hunk.iter().filter( |line| line.tag != '-' ).count();
+        // An empty side starts at the line before the hunk
+        let old_start: usize = if old_count == 0 { hunk[0].old_idx } else { hunk[0].old_idx + 1 };
+        let new_start: usize = if new_count == 0 { hunk[0].new_idx } else { hunk[0].new_idx + 1 };
+        out.push( paint(
+            &format!( "@@ -{},{} +{},{} @@", old_start, old_count, new_start, new_count ),
+            color,
+            |s| s.cyan(),
+        ) );
+
+        for line in hunk {
+            let text: String = format!( "{}{}", line.tag, line.text );
+            out.push( match line.tag {
+                '-' => paint( &text, color, |s| s.red() ),
+                '+' => paint( &text, color, |s| s.green() ),
+                _ => text,
+            } );
+            let last_old: bool = line.tag != '+' && line.old_idx + 1 == old_len && !original.ends_with( '\n' );
+            let last_new: bool = line.tag != '-' && line.new_idx + 1 == new_len && !modified.ends_with( '\n' );
+            if last_old || last_new {
+                out.push( NO_NEWLINE.to_string() );
+            }
+        }
+    }
+
+    out.join( "\n" ) + "\n"
+}
+
+/// Combines edits that apply one after the other into a single edit of the
+/// original text, for consumers that need every change relative to it, such
+/// as an LSP `WorkspaceEdit`
+pub fn combine_edits( original: &str, edits: &[TextEdit] ) -> TextEdit {
+    let (segments, _): (Vec<Segment>, String) = compose_edits( original, edits );
+    // Flushes whatever is pending at the end of the text
+    let end: Segment = Segment::Original( original.len()..original.len() );
+
+    let mut builder = TextEdit::builder();
+    let mut old_pos: usize = 0;
+    let mut insert: String = String::new();
+    for segment in segments.iter().chain( [ &end ] ) {
+        match segment {
+            Segment::Inserted( text ) => insert.push_str( text ),
+            Segment::Original( range ) => {
+                if range.start > old_pos || !insert.is_empty() {
+                    let delete: TextRange = TextRange::new(
+                        TextSize::new( old_pos as u32 ),
+                        TextSize::new( range.start as u32 ),
+                    );
+                    builder.replace( delete, std::mem::take( &mut insert ) );
+                }
+                old_pos = range.end;
+            },
+        }
+    }
+    builder.finish()
+}
+
+/// Applies the edits to the original text, keeping track of which parts of the
+/// result are still original text. Returns the parts and the edited text.
+fn compose_edits( original: &str, edits: &[TextEdit] ) -> (Vec<Segment>, String) {
+    let mut segments: Vec<Segment> = vec![ Segment::Original( 0..original.len() ) ];
+    let mut text: String = original.to_string();
+    for edit in edits {
+        // The indels of an edit are all relative to the text before it, so
+        // applying them from the end keeps the earlier offsets valid
+        for indel in edit.iter().rev() {
+            let start: usize = indel.delete.start().into();
+            let end: usize = indel.delete.end().into();
+            let insert: &str = &indel.insert;
+
+            // Edits often replace more than they change, e.g. a whole block
+            // for one new line. Only the changed part should be in the diff.
+            let deleted: &str = &text[start..end];
+            let prefix: usize = common_len( deleted.chars(), insert.chars() );
+            let suffix: usize = common_len( deleted[prefix..].chars().rev(), insert[prefix..].chars().rev() );
+            segments = splice(
+                segments,
+                start + prefix..end - suffix,
+                &insert[prefix..insert.len() - suffix],
+            );
+            text.replace_range( start..end, insert );
+        }
+    }
+    (segments, text)
+}
+
+/// The length in bytes of the common start of two sequences of chars
+fn common_len( left: impl Iterator<Item = char>, right: impl Iterator<Item = char> ) -> usize {
+    left.zip( right )
+        .take_while( |(l, r)| l == r )
+        .map( |(l, _)| l.len_utf8() )
+        .sum()
+}
+
+/// Replaces `range` of the text made up by `segments` with `insert`
+fn splice( segments: Vec<Segment>, range: Range<usize>, insert: &str ) -> Vec<Segment> {
+    let mut spliced: Vec<Segment> = vec![];
+    let mut inserted: bool = insert.is_empty();
+    let mut pos: usize = 0;
+    for segment in segments {
+        let (seg_start, seg_end): (usize, usize) = ( pos, pos + segment.len() );
+        pos = seg_end;
+        if seg_start < range.start {
+            spliced.push( segment.slice( 0, range.start.min( seg_end ) - seg_start ) );
+        }
+        if !inserted && seg_end >= range.start {
+            spliced.push( Segment::Inserted( insert.to_string() ) );
+            inserted = true;
+        }
+        if seg_end > range.end {
+            spliced.push( segment.slice( range.end.max( seg_start ) - seg_start, seg_end - seg_start ) );
+        }
+    }
+    if !inserted {
+        spliced.push( Segment::Inserted( insert.to_string() ) );
+    }
+    spliced
+}
+
+/// The byte range of each line of `text`, including its line ending
+fn line_ranges( text: &str ) -> Vec<Range<usize>> {
+    let mut start: usize = 0;
+    text.split_inclusive( '\n' )
+        .map( |line| {
+            start += line.len();
+            start - line.len()..start
+        } )
+        .collect()
+}
+
+/// Numbers the lines of the original and modified text. A line that the edits
+/// kept whole, and that is still a whole line afterwards, is context. All other
+/// lines are removed or added.
+fn edit_lines<'a>( original: &'a str, modified: &'a str, segments: &[Segment] ) -> Vec<DiffLine<'a>> {
+    let old_lines: Vec<Range<usize>> = line_ranges( original );
+    let new_lines: Vec<Range<usize>> = line_ranges( modified );
+
+    // Pairs of (old, new) line indices of the unchanged lines, in order
+    let mut kept: Vec<(usize, usize)> = vec![];
+    let mut pos: usize = 0;
+    for segment in segments {
+        if let Segment::Original( range ) = segment {
+            for (old_idx, line) in old_lines.iter().enumerate() {
+                if line.start < range.start || line.end > range.end {
+                    continue;
+                }
+                let moved: Range<usize> = pos + line.start - range.start..pos + line.end - range.start;
+                if let Ok( new_idx ) = new_lines.binary_search_by_key( &moved.start, |line| line.start ) {
+                    if new_lines[new_idx] == moved {
+                        kept.push( ( old_idx, new_idx ) );
+                    }
+                }
+            }
+        }
+        pos += segment.len();
+    }
+
+    let line_text = |text: &'a str, line: &Range<usize>| -> &'a str {
+        let line: &str = &text[line.clone()];
+        line.strip_suffix( '\n' ).unwrap_or( line )
+    };
+    let mut lines: Vec<DiffLine> = vec![];
+    let (mut old_idx, mut new_idx): (usize, usize) = ( 0, 0 );
+    for (old_kept, new_kept) in kept.into_iter().chain( [ ( old_lines.len(), new_lines.len() ) ] ) {
+        while old_idx < old_kept {
+            lines.push( DiffLine { tag: '-', text: line_text( original, &old_lines[old_idx] ), old_idx, new_idx } );
+            old_idx += 1;
+        }
+        while new_idx < new_kept {
+            lines.push( DiffLine { tag: '+', text: line_text( modified, &new_lines[new_idx] ), old_idx, new_idx } );
+            new_idx += 1;
+        }
+        if old_idx < old_lines.len() {
+            lines.push( DiffLine { tag: ' ', text: line_text( original, &old_lines[old_idx] ), old_idx, new_idx } );
+            old_idx += 1;
+            new_idx += 1;
+        }
+    }
+    lines
+}
+
+/// Groups changed lines into hunks. Changes whose context would overlap share
+/// a hunk. Returns the first and last changed line of each hunk.
+fn group_hunks( changes: &[usize] ) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for &idx in changes {
+        match hunks.last_mut() {
+            Some( (_, last) ) if idx - *last <= 2 * CONTEXT + 1 => *last = idx,
+            _ => hunks.push( ( idx, idx ) ),
+        }
+    }
+    hunks
+}
+
+fn paint( text: &str, color: bool, style: fn( &str ) -> ColoredString ) -> String {
+    if color {
+        style( text ).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_ap_ide::{ TextRange, TextSize };
+
+    fn range_of( text: &str, needle: &str ) -> TextRange {
+        let start: usize = text.find( needle ).unwrap();
+        TextRange::at( TextSize::new( start as u32 ), TextSize::new( needle.len() as u32 ) )
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let original: &str = "fn main() {\n    let a = 1;\n    println!(\"{}\", a);\n}\n";
+        // The second edit is relative to the text after the first
+        let edits: Vec<TextEdit> = vec![
+            TextEdit::replace( range_of( original, "1" ), "fun_name()".to_string() ),
+            TextEdit::insert( TextSize::new( original.len() as u32 + 9 ), "\nfn fun_name() -> i32 {\n    1\n}\n".to_string() ),
+        ];
+        let expected: &str = "\
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,4 +1,8 @@
+ fn main() {
+-    let a = 1;
++    let a = fun_name();
+     println!(\"{}\", a);
+ }
++
++fn fun_name() -> i32 {
++    1
++}
+";
+        assert_eq!( unified_diff( original, &edits, "src/main.rs", false ), expected );
+        assert_eq!( unified_diff( original, &[], "src/main.rs", false ), "" );
+    }
+
+    #[test]
+    fn test_unified_diff_inserted_lines() {
+        let original: &str = "fn a() {}\nfn b() {}";
+        let edits: Vec<TextEdit> = vec![ TextEdit::insert( TextSize::new( 10 ), "fn c() {}\n".to_string() ) ];
+        let expected: &str = "\
+--- a/f
++++ b/f
+@@ -1,2 +1,3 @@
+ fn a() {}
++fn c() {}
+ fn b() {}
+\\ No newline at end of file
+";
+        assert_eq!( unified_diff( original, &edits, "f", false ), expected );
+    }
+
+    #[test]
+    fn test_combine_edits() {
+        let original: &str = "fn main() {\n    let a = 1;\n}\n";
+        let edits: Vec<TextEdit> = vec![
+            TextEdit::replace( range_of( original, "1" ), "fun_name()".to_string() ),
+            TextEdit::insert( TextSize::new( original.len() as u32 + 9 ), "\nfn fun_name() {}\n".to_string() ),
+            // Renames the call made by the first edit
+            TextEdit::replace( TextRange::at( TextSize::new( 24 ), TextSize::new( 8 ) ), "one".to_string() ),
+        ];
+        let combined: TextEdit = combine_edits( original, &edits );
+        assert_eq!( combined.len(), 2 );
+
+        let mut text: String = original.to_string();
+        combined.apply( &mut text );
+        assert_eq!( text, "fn main() {\n    let a = one();\n}\n\nfn fun_name() {}\n" );
+    }
+
+    #[test]
+    fn test_unified_diff_separate_hunks() {
+        let original: String = ( 1..=20 ).map( |n| format!( "{}\n", n ) ).collect();
+        let mut builder = TextEdit::builder();
+        builder.replace( range_of( &original, "\n2\n" ), "\ntwo\n".to_string() );
+        builder.replace( range_of( &original, "\n19\n" ), "\nnineteen\n".to_string() );
+        let diff: String = unified_diff( &original, &[ builder.finish() ], "f", false );
+        assert!( diff.contains( "@@ -1,5 +1,5 @@" ), "{}", diff );
+        assert!( diff.contains( "@@ -16,5 +16,5 @@" ), "{}", diff );
+    }
+}
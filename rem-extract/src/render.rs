@@ -0,0 +1,133 @@
+//! Human readable error reports, in the style of rustc's diagnostics:
+//!
+//! ```plaintext
+//! error[NoExtractFunction]: Extract function is not applicable: ...
+//!  --> src/main.rs:3:5
+//!   |
+//! 3 |     let a = 1;
+//!   |     ^^^^^^^^^^
+//! ```
+
+use ra_ap_ide::{
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_ide_db::line_index::{
+    LineCol,
+    LineIndex,
+};
+
+use ra_ap_syntax::{
+    AstNode,
+    SourceFile,
+};
+
+use crate::error::ExtractionError;
+
+/// Lines of a selection shown before the rest are elided
+const MAX_LINES: u32 = 8;
+
+/// Renders an error, with the source it refers to underlined if possible.
+/// The range is taken from the error, or falls back to `selection`.
+pub fn render_error(
+    error: &ExtractionError,
+    path: &str,
+    source_file: Option<&SourceFile>,
+    selection: Option<TextRange>,
+) -> String {
+    let mut out: String = format!( "error[{}]: {}\n", error.kind(), error );
+    if let (Some( source_file ), Some( range )) = (source_file, error.range().or( selection )) {
+        out.push_str( &render_snippet( source_file, path, range ) );
+    }
+    out
+}
+
+/// Renders the lines covered by `range`, with the range underlined
+pub fn render_snippet(
+    source_file: &SourceFile,
+    path: &str,
+    range: TextRange,
+) -> String {
+    let text: String = source_file.syntax().text().to_string();
+    let line_index: LineIndex = LineIndex::new( &text );
+    let start: LineCol = line_index.line_col( clamp_offset( &text, range.start() ) );
+    let end: LineCol = line_index.line_col( clamp_offset( &text, range.end() ) );
+
+    // A selection ending at the start of a line doesn't include that line
+    let last_line: u32 = if end.col == 0 && end.line > start.line { end.line - 1 } else { end.line };
+    let shown_last: u32 = last_line.min( start.line + MAX_LINES - 1 );
+    let width: usize = ( shown_last + 1 ).to_string().len();
+    let pad: String = " ".repeat( width );
+
+    let first_line: &str = line_text( &text, &line_index, start.line );
+    let col: usize = first_line[..( start.col as usize ).min( first_line.len() )].chars().count() + 1;
+    let mut out: String = format!( "{}--> {}:{}:{}\n{} |\n", pad, path, start.line + 1, col, pad );
+
+    for line in start.line..=shown_last {
+        let line_str: &str = line_text( &text, &line_index, line );
+        let from: usize = if line == start.line { start.col as usize } else { 0 };
+        let to: usize = if line == end.line { end.col as usize } else { line_str.len() };
+        let from: usize = from.min( line_str.len() );
+        let to: usize = to.clamp( from, line_str.len() );
+
+        // Keep tabs, so the carets line up with the source
+        let indent: String = line_str[..from]
+            .chars()
+            .map( |c| if c == '\t' { '\t' } else { ' ' } )
+            .collect();
+        let carets: String = "^".repeat( line_str[from..to].chars().count().max( 1 ) );
+
+        out.push_str( &format!( "{:>width$} | {}\n", line + 1, line_str, width = width ) );
+        out.push_str( &format!( "{} | {}{}\n", pad, indent, carets ) );
+    }
+    if shown_last < last_line {
+        out.push_str( &format!( "{} | ... ({} more lines)\n", pad, last_line - shown_last ) );
+    }
+    out
+}
+
+/// The text of a line, without its line ending
+fn line_text<'a>( text: &'a str, line_index: &LineIndex, line: u32 ) -> &'a str {
+    let start: usize = line_index
+        .offset( LineCol { line, col: 0 } )
+        .map_or( text.len(), usize::from );
+    let end: usize = line_index
+        .offset( LineCol { line: line + 1, col: 0 } )
+        .map_or( text.len(), usize::from );
+    text[start..end].trim_end_matches( [ '\n', '\r' ] )
+}
+
+/// Keeps an offset inside the text and on a character boundary
+fn clamp_offset( text: &str, offset: TextSize ) -> TextSize {
+    let mut offset: usize = usize::from( offset ).min( text.len() );
+    while !text.is_char_boundary( offset ) {
+        offset -= 1;
+    }
+    TextSize::new( offset as u32 )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_ap_syntax::Edition;
+
+    #[test]
+    fn test_render_snippet() {
+        let text: &str = "fn main() {\n    let a = 1;\n    let b = 2;\n}\n";
+        let source_file: SourceFile = SourceFile::parse( text, Edition::CURRENT ).tree();
+        let start: u32 = text.find( "let a" ).unwrap() as u32;
+        let end: u32 = text.find( "= 2;" ).unwrap() as u32;
+
+        let snippet: String = render_snippet( &source_file, "src/main.rs", TextRange::new( start.into(), end.into() ) );
+        let expected: &str = "\
+ --> src/main.rs:2:5
+  |
+2 |     let a = 1;
+  |     ^^^^^^^^^^
+3 |     let b = 2;
+  | ^^^^^^^^^^
+";
+        assert_eq!( snippet, expected );
+    }
+}
@@ -0,0 +1,557 @@
+//! Alternative ways of specifying the code to extract.
+//!
+//! `ExtractionInput` takes byte offsets by default. A `Selection` can be given
+//! instead, and is resolved against the text of the file (as the session sees
+//! it) into byte offsets before the extraction runs.
+//!
+//! Marker selections find the range in the file itself, either between
+//! `// rem-extract:start [name]` and `// rem-extract:end` comments, or between
+//! a pair of `$0` markers as in rust-analyzer's fixtures. The markers are
+//! removed from the file before extracting.
+//!
+//! Item selections name a function by path and pick statements of its body,
+//! so they keep working as the file is edited.
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use ra_ap_ide_db::line_index::{
+    LineCol,
+    LineIndex,
+    WideEncoding,
+    WideLineCol,
+};
+
+use ra_ap_hir::{
+    self as hir,
+    HasSource,
+    ModuleDef,
+    Semantics,
+};
+
+use ra_ap_ide::{
+    RootDatabase,
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_syntax::{
+    ast,
+    AstNode,
+};
+
+use ra_ap_vfs::FileId;
+
+use crate::error::ExtractionError;
+
+/// How the column of a `Position` is counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnUnit {
+    /// UTF-8 bytes
+    #[default]
+    Bytes,
+    /// Unicode scalar values
+    Chars,
+    /// UTF-16 code units, as used by LSP
+    Utf16,
+}
+
+impl FromStr for ColumnUnit {
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" | "byte" | "utf8" => Ok( ColumnUnit::Bytes ),
+            "chars" | "char" | "utf32" => Ok( ColumnUnit::Chars ),
+            "utf16" | "utf-16" => Ok( ColumnUnit::Utf16 ),
+            _ => Err( format!( "unknown column unit `{}` (expected bytes, chars or utf16)", s ) ),
+        }
+    }
+}
+
+/// A 1-based line and column, as printed by rustc and most editors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!( f, "{}:{}", self.line, self.col )
+    }
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    /// Parses `line:col`
+    fn from_str( s: &str ) -> Result<Self, Self::Err> {
+        let (line, col) = s
+            .split_once( ':' )
+            .ok_or_else( || format!( "expected `line:col`, found `{}`", s ) )?;
+        let line: u32 = line.trim().parse().map_err( |_| format!( "invalid line in `{}`", s ) )?;
+        let col: u32 = col.trim().parse().map_err( |_| format!( "invalid column in `{}`", s ) )?;
+        Ok( Position { line, col } )
+    }
+}
+
+/// Which part of a function body to extract
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtTarget {
+    /// Statements `start..=end` of the body, counted from 0
+    Stmts {
+        start: usize,
+        end: usize,
+    },
+    /// The tail expression of the body
+    Tail,
+}
+
+impl FromStr for StmtTarget {
+    type Err = String;
+
+    /// Parses `tail`, `2..=5`, `2..6` or a single index
+    fn from_str( s: &str ) -> Result<Self, Self::Err> {
+        let s: &str = s.trim();
+        if s == "tail" {
+            return Ok( StmtTarget::Tail );
+        }
+        let parse = |idx: &str| -> Result<usize, String> {
+            idx.trim().parse().map_err( |_| format!( "invalid statement index in `{}`", s ) )
+        };
+        let (start, end) = if let Some( (start, end) ) = s.split_once( "..=" ) {
+            ( parse( start )?, parse( end )? )
+        } else if let Some( (start, end) ) = s.split_once( ".." ) {
+            let end: usize = parse( end )?;
+            if end == 0 {
+                return Err( format!( "empty statement range `{}`", s ) );
+            }
+            ( parse( start )?, end - 1 )
+        } else {
+            let idx: usize = parse( s )?;
+            ( idx, idx )
+        };
+        if start > end {
+            return Err( format!( "empty statement range `{}`", s ) );
+        }
+        Ok( StmtTarget::Stmts { start, end } )
+    }
+}
+
+/// The code to extract, if not given as byte offsets
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    /// Start and end line/column positions
+    LineCol {
+        start: Position,
+        end: Position,
+        unit: ColumnUnit,
+    },
+    /// The code between marker comments (or `$0` markers). If `name` is set,
+    /// only the start marker with that name is used.
+    /// The offsets in the `ExtractionOutput` are relative to the file with the
+    /// selected markers removed. Other marker pairs are left in place.
+    Markers {
+        name: Option<String>,
+    },
+    /// Statements of a function, named by a path such as
+    /// `crate::parser::Parser::parse_expr`. The function must be defined in
+    /// the input file.
+    Item {
+        path: String,
+        target: StmtTarget,
+    },
+}
+
+/// A selection resolved against the text of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedSelection {
+    /// Byte offsets into `text` if it is set, otherwise into the original text
+    pub range: (u32, u32),
+    /// The text with the markers removed, if there were any
+    pub text: Option<String>,
+    /// The name given on the start marker
+    pub name: Option<String>,
+}
+
+const START_MARKER: &str = "// rem-extract:start";
+const END_MARKER: &str = "// rem-extract:end";
+const CURSOR_MARKER: &str = "$0";
+
+impl Selection {
+    /// Parses a range in the form `12:5-18:2`
+    pub fn parse_line_col( range: &str, unit: ColumnUnit ) -> Result<Self, String> {
+        let (start, end) = range
+            .split_once( '-' )
+            .ok_or_else( || format!( "expected `line:col-line:col`, found `{}`", range ) )?;
+        Ok( Selection::LineCol {
+            start: start.parse()?,
+            end: end.parse()?,
+            unit,
+        } )
+    }
+
+    /// Resolves the selection against `text`, the current text of `file_id`.
+    /// Item selections are resolved through `Semantics`.
+    pub fn resolve_in(
+        &self,
+        sema: &Semantics<'_, RootDatabase>,
+        file_id: FileId,
+        text: &str,
+    ) -> Result<ResolvedSelection, ExtractionError> {
+        match self {
+            Selection::Item { path, target } => Ok( ResolvedSelection {
+                range: resolve_item( sema, file_id, path, target )?,
+                text: None,
+                name: None,
+            } ),
+            _ => self.resolve( text ),
+        }
+    }
+
+    /// Resolves the selection against `text`. Item selections can't be
+    /// resolved from the text alone, use `resolve_in` for those.
+    pub fn resolve( &self, text: &str ) -> Result<ResolvedSelection, ExtractionError> {
+        match self {
+            Selection::Item { path, .. } => Err( ExtractionError::ItemNotFound(
+                format!( "{} (item selections need to be resolved with `resolve_in`)", path )
+            ) ),
+            Selection::LineCol { start, end, unit } => {
+                let line_index: LineIndex = LineIndex::new( text );
+                Ok( ResolvedSelection {
+                    range: (
+                        position_to_offset( &line_index, text, *start, *unit )?,
+                        position_to_offset( &line_index, text, *end, *unit )?,
+                    ),
+                    text: None,
+                    name: None,
+                } )
+            }
+            Selection::Markers { name } => {
+                if text.lines().any( |line| is_marker_line( line ) ) {
+                    resolve_comment_markers( text, name.as_deref() )
+                } else if name.is_none() {
+                    resolve_cursor_markers( text )
+                } else {
+                    Err( ExtractionError::InvalidMarkers( format!( "no `{}` markers found", START_MARKER ) ) )
+                }
+            }
+        }
+    }
+}
+
+fn is_marker_line( line: &str ) -> bool {
+    let line: &str = line.trim();
+    line.starts_with( START_MARKER ) || line.starts_with( END_MARKER )
+}
+
+/// Finds the code between the start marker (with the given name, or the first
+/// one) and the next end marker. Only the lines of that pair of markers are
+/// removed from the text, so the other regions can still be selected.
+fn resolve_comment_markers(
+    text: &str,
+    name: Option<&str>,
+) -> Result<ResolvedSelection, ExtractionError> {
+    let mut stripped: String = String::with_capacity( text.len() );
+    let mut found: Option<String> = None;
+    let mut start: Option<usize> = None;
+    let mut end: Option<usize> = None;
+    let mut open: bool = false;
+    // Whether the open region is the selected one
+    let mut selected: bool = false;
+
+    for line in text.split_inclusive( '\n' ) {
+        let trimmed: &str = line.trim();
+        if let Some( rest ) = trimmed.strip_prefix( START_MARKER ) {
+            if open {
+                return Err( ExtractionError::InvalidMarkers( "nested start markers".to_string() ) );
+            }
+            open = true;
+            let marker_name: Option<String> = Some( rest.trim() )
+                .filter( |rest| !rest.is_empty() )
+                .map( |rest| rest.to_string() );
+            if start.is_none() && ( name.is_none() || marker_name.as_deref() == name ) {
+                start = Some( stripped.len() );
+                found = marker_name;
+                selected = true;
+                continue;
+            }
+        } else if trimmed.starts_with( END_MARKER ) {
+            if !open {
+                return Err( ExtractionError::InvalidMarkers( "end marker without a start marker".to_string() ) );
+            }
+            open = false;
+            if selected {
+                selected = false;
+                end = Some( stripped.len() );
+                continue;
+            }
+        }
+        stripped.push_str( line );
+    }
+
+    if open {
+        return Err( ExtractionError::InvalidMarkers( "start marker without an end marker".to_string() ) );
+    }
+    match (start, end) {
+        (Some( start ), Some( end )) => Ok( ResolvedSelection {
+            range: ( start as u32, end as u32 ),
+            text: Some( stripped ),
+            name: found,
+        } ),
+        _ => Err( ExtractionError::InvalidMarkers( match name {
+            Some( name ) => format!( "no start marker named `{}`", name ),
+            None => "no start marker".to_string(),
+        } ) ),
+    }
+}
+
+/// Finds the code between a pair of `$0` markers, and removes them
+fn resolve_cursor_markers( text: &str ) -> Result<ResolvedSelection, ExtractionError> {
+    let markers: Vec<usize> = text
+        .match_indices( CURSOR_MARKER )
+        .map( |(idx, _)| idx )
+        .collect();
+    if markers.len() != 2 {
+        return Err( ExtractionError::InvalidMarkers(
+            format!( "expected two `{}` markers, found {}", CURSOR_MARKER, markers.len() )
+        ) );
+    }
+
+    let start: usize = markers[0];
+    let end: usize = markers[1] - CURSOR_MARKER.len();
+    Ok( ResolvedSelection {
+        range: ( start as u32, end as u32 ),
+        text: Some( text.replacen( CURSOR_MARKER, "", 2 ) ),
+        name: None,
+    } )
+}
+
+/// Finds the function named by `path` and returns the range of the targeted
+/// statements in its body
+fn resolve_item(
+    sema: &Semantics<'_, RootDatabase>,
+    file_id: FileId,
+    path: &str,
+    target: &StmtTarget,
+) -> Result<(u32, u32), ExtractionError> {
+    let db: &RootDatabase = sema.db;
+    let not_found = || ExtractionError::ItemNotFound( path.to_string() );
+
+    let module: hir::Module = sema.file_to_module_def( file_id ).ok_or_else( not_found )?;
+    let function: hir::Function = find_function( db, module.krate().root_module(), path )
+        .ok_or_else( not_found )?;
+    let src: hir::InFile<ast::Fn> = function.source( db ).ok_or_else( not_found )?;
+    if src.file_id.file_id().map( |id| id.file_id() ) != Some( file_id ) {
+        return Err( ExtractionError::ItemNotFound( format!( "{} is not defined in the input file", path ) ) );
+    }
+
+    let stmt_list: ast::StmtList = src.value
+        .body()
+        .and_then( |body| body.stmt_list() )
+        .ok_or_else( || ExtractionError::InvalidStmtSelection( format!( "{} has no body", path ) ) )?;
+
+    let range: TextRange = match target {
+        StmtTarget::Tail => stmt_list
+            .tail_expr()
+            .map( |expr| expr.syntax().text_range() )
+            .ok_or_else( || ExtractionError::InvalidStmtSelection( format!( "{} has no tail expression", path ) ) )?,
+        StmtTarget::Stmts { start, end } => {
+            let stmts: Vec<ast::Stmt> = stmt_list.statements().collect();
+            match (stmts.get( *start ), stmts.get( *end )) {
+                (Some( first ), Some( last )) if start <= end => first.syntax().text_range().cover( last.syntax().text_range() ),
+                _ => return Err( ExtractionError::InvalidStmtSelection(
+                    format!( "{}..={} is out of range, {} has {} statements", start, end, path, stmts.len() )
+                ) ),
+            }
+        },
+    };
+
+    Ok( ( range.start().into(), range.end().into() ) )
+}
+
+/// Walks the module tree from the crate root. The last segment is the function,
+/// and the one before it may be a type or trait the function is associated
+/// with.
+fn find_function( db: &RootDatabase, root: hir::Module, path: &str ) -> Option<hir::Function> {
+    let mut segments: Vec<&str> = path.split( "::" ).map( str::trim ).collect();
+    if segments.first() == Some( &"crate" ) {
+        segments.remove( 0 );
+    }
+    let (fn_name, parents) = segments.split_last()?;
+
+    let mut module: hir::Module = root;
+    for (idx, segment) in parents.iter().enumerate() {
+        let child: Option<hir::Module> = module
+            .children( db )
+            .find( |child| child.name( db ).map_or( false, |name| name.as_str() == *segment ) );
+        if let Some( child ) = child {
+            module = child;
+            continue;
+        }
+
+        // Not a module, so it has to be the type or trait of a method
+        if idx + 1 != parents.len() {
+            return None;
+        }
+        return module
+            .declarations( db )
+            .into_iter()
+            .filter( |def| def.name( db ).map_or( false, |name| name.as_str() == *segment ) )
+            .find_map( |def| find_assoc_fn( db, def, fn_name ) );
+    }
+
+    module
+        .declarations( db )
+        .into_iter()
+        .find_map( |def| match def {
+            ModuleDef::Function( function ) if function.name( db ).as_str() == *fn_name => Some( function ),
+            _ => None,
+        } )
+}
+
+/// Looks for a function in the impls of a type, or in a trait
+fn find_assoc_fn( db: &RootDatabase, def: ModuleDef, name: &str ) -> Option<hir::Function> {
+    let items: Vec<hir::AssocItem> = match def {
+        ModuleDef::Adt( adt ) => hir::Impl::all_for_type( db, adt.ty( db ) )
+            .into_iter()
+            .flat_map( |impl_| impl_.items( db ) )
+            .collect(),
+        ModuleDef::TypeAlias( alias ) => hir::Impl::all_for_type( db, alias.ty( db ) )
+            .into_iter()
+            .flat_map( |impl_| impl_.items( db ) )
+            .collect(),
+        ModuleDef::Trait( trait_ ) => trait_.items( db ),
+        _ => return None,
+    };
+
+    items.into_iter().find_map( |item| match item {
+        hir::AssocItem::Function( function ) if function.name( db ).as_str() == name => Some( function ),
+        _ => None,
+    } )
+}
+
+/// Converts a 1-based position to a byte offset with the `LineIndex`.
+/// Positions past the end of their line, or inside a character, are rejected.
+fn position_to_offset(
+    line_index: &LineIndex,
+    text: &str,
+    position: Position,
+    unit: ColumnUnit,
+) -> Result<u32, ExtractionError> {
+    let invalid = |reason: &'static str| ExtractionError::InvalidPosition { position, reason };
+
+    if position.line == 0 || position.col == 0 {
+        return Err( invalid( "lines and columns start at 1" ) );
+    }
+    let line: u32 = position.line - 1;
+    let col: u32 = position.col - 1;
+
+    let line_col: LineCol = match unit {
+        ColumnUnit::Bytes => LineCol { line, col },
+        ColumnUnit::Chars => line_index
+            .to_utf8( WideEncoding::Utf32, WideLineCol { line, col } )
+            .ok_or( invalid( "the column is past the end of the line" ) )?,
+        ColumnUnit::Utf16 => line_index
+            .to_utf8( WideEncoding::Utf16, WideLineCol { line, col } )
+            .ok_or( invalid( "the column is past the end of the line" ) )?,
+    };
+
+    let offset: TextSize = line_index
+        .offset( line_col )
+        .ok_or( invalid( "the line is past the end of the file" ) )?;
+    if usize::from( offset ) > text.len() || !text.is_char_boundary( offset.into() ) {
+        return Err( invalid( "the column is not on a character boundary" ) );
+    }
+    // `offset` doesn't check the column against the length of the line
+    if line_index.line_col( offset ) != line_col {
+        return Err( invalid( "the column is past the end of the line" ) );
+    }
+
+    Ok( offset.into() )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn main() {\n    let s = \"héllo\"; let n = 1;\n}\n";
+
+    #[test]
+    fn test_parse_line_col() {
+        let selection: Selection = Selection::parse_line_col( "12:5-18:2", ColumnUnit::Utf16 ).unwrap();
+        assert_eq!( selection, Selection::LineCol {
+            start: Position { line: 12, col: 5 },
+            end: Position { line: 18, col: 2 },
+            unit: ColumnUnit::Utf16,
+        } );
+        assert!( Selection::parse_line_col( "12:5", ColumnUnit::Bytes ).is_err() );
+    }
+
+    #[test]
+    fn test_parse_stmt_target() {
+        assert_eq!( "tail".parse::<StmtTarget>(), Ok( StmtTarget::Tail ) );
+        assert_eq!( "2..=5".parse::<StmtTarget>(), Ok( StmtTarget::Stmts { start: 2, end: 5 } ) );
+        assert_eq!( "2..6".parse::<StmtTarget>(), Ok( StmtTarget::Stmts { start: 2, end: 5 } ) );
+        assert_eq!( "3".parse::<StmtTarget>(), Ok( StmtTarget::Stmts { start: 3, end: 3 } ) );
+        assert!( "5..=2".parse::<StmtTarget>().is_err() );
+        assert!( "2..2".parse::<StmtTarget>().is_err() );
+    }
+
+    #[test]
+    fn test_resolve_units() {
+        // `let n` comes after a two byte character on the same line
+        let expected: u32 = TEXT.find( "let n" ).unwrap() as u32;
+        let bytes: Selection = Selection::parse_line_col( "2:23-3:1", ColumnUnit::Bytes ).unwrap();
+        let chars: Selection = Selection::parse_line_col( "2:22-3:1", ColumnUnit::Chars ).unwrap();
+        let utf16: Selection = Selection::parse_line_col( "2:22-3:1", ColumnUnit::Utf16 ).unwrap();
+        for selection in [bytes, chars, utf16] {
+            assert_eq!( selection.resolve( TEXT ).unwrap().range.0, expected );
+        }
+    }
+
+    #[test]
+    fn test_resolve_comment_markers() {
+        let text: &str = "fn main() {\n    // rem-extract:start first\n    let a = 1;\n    // rem-extract:end\n    // rem-extract:start second\n    let b = 2;\n    // rem-extract:end\n}\n";
+        let resolved: ResolvedSelection = Selection::Markers { name: Some( "second".to_string() ) }
+            .resolve( text )
+            .unwrap();
+
+        // Only the selected pair of markers is removed
+        let stripped: String = resolved.text.unwrap();
+        assert_eq!( stripped, "fn main() {\n    // rem-extract:start first\n    let a = 1;\n    // rem-extract:end\n    let b = 2;\n}\n" );
+        assert_eq!( &stripped[resolved.range.0 as usize..resolved.range.1 as usize], "    let b = 2;\n" );
+        assert_eq!( resolved.name.as_deref(), Some( "second" ) );
+
+        // So the other region can be selected afterwards
+        let resolved: ResolvedSelection = Selection::Markers { name: Some( "first".to_string() ) }
+            .resolve( &stripped )
+            .unwrap();
+        let stripped: String = resolved.text.unwrap();
+        assert_eq!( stripped, "fn main() {\n    let a = 1;\n    let b = 2;\n}\n" );
+        assert_eq!( &stripped[resolved.range.0 as usize..resolved.range.1 as usize], "    let a = 1;\n" );
+
+        let unclosed: &str = "fn main() {\n    // rem-extract:start\n    let a = 1;\n}\n";
+        assert!( Selection::Markers { name: None }.resolve( unclosed ).is_err() );
+    }
+
+    #[test]
+    fn test_resolve_cursor_markers() {
+        let text: &str = "fn main() {\n    $0let a = 1;$0\n}\n";
+        let resolved: ResolvedSelection = Selection::Markers { name: None }.resolve( text ).unwrap();
+
+        let stripped: String = resolved.text.unwrap();
+        assert_eq!( stripped, "fn main() {\n    let a = 1;\n}\n" );
+        assert_eq!( &stripped[resolved.range.0 as usize..resolved.range.1 as usize], "let a = 1;" );
+    }
+
+    #[test]
+    fn test_resolve_rejects_invalid_positions() {
+        assert!( Selection::parse_line_col( "2:80-3:1", ColumnUnit::Bytes ).unwrap().resolve( TEXT ).is_err() );
+        assert!( Selection::parse_line_col( "9:1-9:2", ColumnUnit::Bytes ).unwrap().resolve( TEXT ).is_err() );
+        assert!( Selection::parse_line_col( "0:1-1:2", ColumnUnit::Bytes ).unwrap().resolve( TEXT ).is_err() );
+    }
+}
@@ -0,0 +1,247 @@
+//! A JSON-RPC 2.0 server over stdin/stdout, so editors and `rem-cli` can keep
+//! workspaces loaded between extractions.
+//!
+//! Messages are single line JSON objects, one per line. The methods are:
+//!
+//! - `workspace/open { file }`: loads the workspace containing `file`, and
+//!   returns `{ workspace, missing_out_dirs, crates, workspace_crates,
+//!   timings }`: the directory of its `Cargo.toml`, the packages whose build
+//!   scripts weren't run, the crates loaded out of those in the workspace,
+//!   and how long loading took.
+//! - `workspace/close { workspace }`: drops a loaded workspace.
+//! - `file/update { file, text }`: replaces the contents of a file with unsaved
+//!   text. A `null` text goes back to the contents on disk.
+//! - `extract { file, name, ... }`: runs an extraction, and returns the same
+//!   object as `--format json`. The selection (and any unsaved `contents` or
+//!   `overlays`) is given as in a `batch` request.
+//! - `check { file, name, ... }`: checks whether the selection can be
+//!   extracted, without changing anything. Returns `{ applicable, range }` or
+//!   `{ applicable, error }`.
+//! - `shutdown`: stops the server once the response is written.
+//!
+//! Workspaces are loaded on demand if a file outside the open workspaces is
+//! used. Extraction errors are returned with code `-32000`, and the `kind`,
+//! `exit_code`, `message` and `range` of the error as their data.
+
+use std::{
+    fs,
+    io::{
+        self,
+        BufRead,
+        Write,
+    },
+    path::PathBuf,
+};
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+};
+
+use crate::{
+    batch::{
+        input_from_json,
+        str_field,
+    },
+    config::LoadOptions,
+    error::ExtractionError,
+    extraction::{
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    extraction_utils::convert_to_abs_path_buf,
+    json::{
+        error_details,
+        output_to_json,
+        range_to_json,
+        timings_to_json,
+    },
+    session::SessionCache,
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const EXTRACTION_FAILED: i64 = -32000;
+
+/// A JSON-RPC error response
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new( code: i64, message: impl Into<String> ) -> Self {
+        RpcError { code, message: message.into(), data: None }
+    }
+}
+
+impl From<ExtractionError> for RpcError {
+    fn from( error: ExtractionError ) -> Self {
+        let code: i64 = match error {
+            ExtractionError::InvalidRequest( _ ) => INVALID_PARAMS,
+            _ => EXTRACTION_FAILED,
+        };
+        RpcError {
+            code,
+            message: error.to_string(),
+            data: Some( error_details( &error ) ),
+        }
+    }
+}
+
+/// The state of the server: the workspaces it has loaded
+#[derive(Default)]
+pub struct Server {
+    sessions: SessionCache,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    pub fn with_options( options: LoadOptions ) -> Self {
+        Server {
+            sessions: SessionCache::with_options( options ),
+            shutdown: false,
+        }
+    }
+
+    /// Whether a `shutdown` request has been handled
+    pub fn is_shutdown( &self ) -> bool {
+        self.shutdown
+    }
+
+    /// Handles a single message, and returns the response to send, if any.
+    /// Notifications (messages without an `id`) get no response.
+    pub fn handle_message( &mut self, message: &str ) -> Option<Value> {
+        let message: Value = match serde_json::from_str( message ) {
+            Ok( message ) => message,
+            Err( e ) => return Some( error_response( Value::Null, RpcError::new( PARSE_ERROR, e.to_string() ) ) ),
+        };
+        let id: Option<Value> = message.get( "id" ).cloned();
+
+        let method: &str = match ( message.get( "jsonrpc" ), message.get( "method" ) ) {
+            ( Some( version ), Some( Value::String( method ) ) ) if version == "2.0" => method,
+            _ => return Some( error_response(
+                id.unwrap_or( Value::Null ),
+                RpcError::new( INVALID_REQUEST, "expected a JSON-RPC 2.0 request" ),
+            ) ),
+        };
+        let params: Map<String, Value> = match message.get( "params" ) {
+            None | Some( Value::Null ) => Map::new(),
+            Some( Value::Object( params ) ) => params.clone(),
+            Some( _ ) => return id.map( |id| error_response(
+                id,
+                RpcError::new( INVALID_PARAMS, "params must be an object" ),
+            ) ),
+        };
+
+        let result: Result<Value, RpcError> = self.dispatch( method, &params );
+        id.map( |id| match result {
+            Ok( result ) => json!( { "jsonrpc": "2.0", "id": id, "result": result } ),
+            Err( error ) => error_response( id, error ),
+        } )
+    }
+
+    fn dispatch( &mut self, method: &str, params: &Map<String, Value> ) -> Result<Value, RpcError> {
+        match method {
+            "workspace/open" => {
+                let (workspace, session) = self.sessions.get_or_load( &required_str( params, "file" )? )?;
+                let (crates, workspace_crates) = session.crates();
+                Ok( json!( {
+                    "workspace": workspace.to_string_lossy(),
+                    "missing_out_dirs": session.missing_out_dirs(),
+                    "crates": crates,
+                    "workspace_crates": workspace_crates,
+                    "timings": timings_to_json( session.load_timings() ),
+                } ) )
+            },
+            "workspace/close" => {
+                let workspace: PathBuf = PathBuf::from( required_str( params, "workspace" )? );
+                Ok( json!( { "closed": self.sessions.remove( &workspace ) } ) )
+            },
+            "file/update" => {
+                let file: String = required_str( params, "file" )?;
+                let text: String = match str_field( params, "text" )? {
+                    Some( text ) => text,
+                    None => fs::read_to_string( &file ).map_err( ExtractionError::from )?,
+                };
+                let abs_path: AbsPathBuf = abs_path( &file )?;
+                let (_, session) = self.sessions.get_or_load( &file )?;
+                let file_id: FileId = session.file_id( &abs_path )?;
+                session.set_file_text( file_id, text );
+                Ok( Value::Null )
+            },
+            "extract" => {
+                let input: ExtractionInput = input_from_json( params )?;
+                let (_, session) = self.sessions.get_or_load( &input.file_path )?;
+                let output: ExtractionOutput = session.extract( &input )?;
+                Ok( output_to_json( &output ) )
+            },
+            "check" => {
+                let input: ExtractionInput = input_from_json( params )?;
+                let (_, session) = self.sessions.get_or_load( &input.file_path )?;
+                Ok( match session.check( &input ) {
+                    Ok( range ) => json!( { "applicable": true, "range": range_to_json( range ) } ),
+                    Err( e ) => json!( { "applicable": false, "error": error_details( &e ) } ),
+                } )
+            },
+            "shutdown" => {
+                self.shutdown = true;
+                Ok( Value::Null )
+            },
+            _ => Err( RpcError::new( METHOD_NOT_FOUND, format!( "unknown method `{}`", method ) ) ),
+        }
+    }
+}
+
+/// Reads messages from `reader` and writes a response to each request, until
+/// the input ends or a `shutdown` request is handled. Every workspace is
+/// loaded with `options`.
+pub fn serve<R: BufRead, W: Write>( reader: R, writer: &mut W, options: LoadOptions ) -> io::Result<()> {
+    let mut server: Server = Server::with_options( options );
+    for line in reader.lines() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some( response ) = server.handle_message( &line ) {
+            writeln!( writer, "{}", response )?;
+            writer.flush()?;
+        }
+        if server.is_shutdown() {
+            break;
+        }
+    }
+    Ok( () )
+}
+
+fn error_response( id: Value, error: RpcError ) -> Value {
+    let mut body: Value = json!( { "code": error.code, "message": error.message } );
+    if let Some( data ) = error.data {
+        body["data"] = data;
+    }
+    json!( { "jsonrpc": "2.0", "id": id, "error": body } )
+}
+
+fn required_str( params: &Map<String, Value>, key: &str ) -> Result<String, RpcError> {
+    str_field( params, key )?
+        .ok_or_else( || RpcError::new( INVALID_PARAMS, format!( "missing `{}`", key ) ) )
+}
+
+fn abs_path( file: &str ) -> Result<AbsPathBuf, ExtractionError> {
+    convert_to_abs_path_buf( file ).map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )
+}
+
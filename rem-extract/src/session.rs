@@ -0,0 +1,604 @@
+//! A long lived extraction session.
+//!
+//! Loading a workspace (`cargo metadata`, the sysroot and the `RootDatabase`)
+//! dominates the cost of an extraction. An `ExtractionSession` does this once
+//! and then serves as many extractions as are needed. Every accepted edit is
+//! applied back into the `AnalysisHost`, so chained extractions on the same
+//! file see the updated source without reloading the workspace.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::Instant,
+};
+
+use ra_ap_hir::{
+    ChangeWithProcMacros,
+    Semantics,
+};
+
+use ra_ap_ide::{
+    Analysis,
+    AnalysisHost,
+    RootDatabase,
+    TextEdit,
+    TextRange,
+};
+
+use ra_ap_ide_db::{
+    base_db::SourceDatabase,
+    EditionedFileId,
+};
+
+use ra_ap_project_model::{
+    CargoConfig,
+    ProjectWorkspace,
+    ProjectWorkspaceKind,
+    ProjectManifest,
+};
+
+use ra_ap_ide_assists::Assist;
+
+use ra_ap_syntax::{
+    Edition,
+    SourceFile,
+};
+
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+    Vfs,
+};
+
+use crate::{
+    config::LoadOptions,
+    diagnosis::diagnose_selection,
+    error::ExtractionError,
+    extraction::{
+        build_output,
+        check_fn_name,
+        check_idx,
+        verify_input,
+        ExtractionInput,
+        ExtractionOutput,
+        Timings,
+    },
+    extraction_utils::{
+        apply_extract_function,
+        check_braces,
+        check_comment,
+        check_name_in_scope,
+        convert_to_abs_path_buf,
+        crate_is_no_std,
+        file_edition,
+        filter_extract_function_assist,
+        generate_frange,
+        get_assists,
+        get_cargo_config,
+        get_cargo_toml,
+        get_file_id,
+        get_manifest_dir,
+        get_workspace_root,
+        load_detached_workspace,
+        load_project_manifest,
+        load_project_workspace,
+        load_scoped_workspace_data,
+        load_workspace_data,
+        packages_with_build_scripts,
+        relative_path,
+        run_analysis,
+        set_crate_edition,
+        snap_range,
+        trim_range,
+    },
+    selection::ResolvedSelection,
+    write::content_hash,
+};
+
+/// Owns the `AnalysisHost` and `Vfs` for a single workspace.
+pub struct ExtractionSession {
+    manifest_dir: PathBuf,
+    /// The root of the cargo workspace, which diff paths are relative to
+    workspace_root: PathBuf,
+    host: AnalysisHost,
+    vfs: Vfs,
+    /// Packages whose build scripts weren't run, so have no `OUT_DIR`
+    missing_out_dirs: Vec<String>,
+    /// How long loading the workspace took
+    load_timings: Timings,
+    /// The crates kept in the crate graph, and the crates in the workspace
+    crates: (usize, usize),
+    /// Whether only the package in `manifest_dir` was loaded
+    scoped: bool,
+}
+
+impl ExtractionSession {
+    /// Loads the workspace that contains the given file.
+    pub fn load( file_path: &str ) -> Result<Self, ExtractionError> {
+        ExtractionSession::load_with_options( file_path, &LoadOptions::default() )
+    }
+
+    /// Loads the workspace that contains the given file, with the given
+    /// features, target, cfgs and `cargo` settings
+    pub fn load_with_options( file_path: &str, options: &LoadOptions ) -> Result<Self, ExtractionError> {
+        let mut load_timings: Timings = Timings::default();
+        let mut timer: Instant = Instant::now();
+
+        let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( file_path )
+            .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+
+        let manifest_dir: PathBuf = get_manifest_dir(
+            &PathBuf::from( input_abs_path.as_str() )
+        )?;
+        let cargo_toml: AbsPathBuf = get_cargo_toml( &manifest_dir )?;
+        let project_manifest: ProjectManifest = load_project_manifest( &cargo_toml )?;
+        let cargo_config: CargoConfig = get_cargo_config( options )?;
+        let workspace: ProjectWorkspace = load_project_workspace( &project_manifest, &cargo_config )?;
+        let workspace_root: PathBuf = match &workspace.kind {
+            ProjectWorkspaceKind::Cargo { cargo, .. } => PathBuf::from( cargo.workspace_root().as_str() ),
+            _ => manifest_dir.clone(),
+        };
+        let missing_out_dirs: Vec<String> = if options.runs_build_scripts() {
+            vec![]
+        } else {
+            packages_with_build_scripts( &workspace )
+        };
+        let (db, vfs, crates) = if options.scoped {
+            load_scoped_workspace_data( workspace, &cargo_config, options, &manifest_dir )?
+        } else {
+            let (db, vfs) = load_workspace_data( workspace, &cargo_config, options )?;
+            let total: usize = db.crate_graph().len();
+            (db, vfs, ( total, total ))
+        };
+        load_timings.lap( "load", &mut timer );
+
+        Ok( ExtractionSession {
+            manifest_dir,
+            workspace_root,
+            host: AnalysisHost::with_database( db ),
+            vfs,
+            missing_out_dirs,
+            load_timings,
+            crates,
+            scoped: options.scoped,
+        } )
+    }
+
+    /// Loads a single file that isn't part of a Cargo project, as a detached
+    /// file: one crate with the given edition, and the sysroot if the options
+    /// ask for it
+    pub fn load_detached( file_path: &str, edition: Edition, options: &LoadOptions ) -> Result<Self, ExtractionError> {
+        let mut load_timings: Timings = Timings::default();
+        let mut timer: Instant = Instant::now();
+
+        let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( file_path )
+            .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+        let dir: PathBuf = PathBuf::from( input_abs_path.as_str() )
+            .parent()
+            .map( Path::to_path_buf )
+            .ok_or_else( || ExtractionError::InvalidPath( file_path.to_string() ) )?;
+
+        let cargo_config: CargoConfig = get_cargo_config( options )?;
+        let workspace: ProjectWorkspace = load_detached_workspace( &input_abs_path, &cargo_config )?;
+        let (mut db, vfs) = load_workspace_data( workspace, &cargo_config, options )?;
+        set_crate_edition( &mut db, get_file_id( &input_abs_path, &vfs )?, edition );
+        load_timings.lap( "load", &mut timer );
+
+        let total: usize = db.crate_graph().len();
+        Ok( ExtractionSession {
+            manifest_dir: dir.clone(),
+            workspace_root: dir,
+            host: AnalysisHost::with_database( db ),
+            vfs,
+            missing_out_dirs: vec![],
+            load_timings,
+            crates: ( total, total ),
+            scoped: false,
+        } )
+    }
+
+    /// The packages with build scripts that weren't run, so whose `OUT_DIR`
+    /// (and anything `include!`d from it) is unknown
+    pub fn missing_out_dirs( &self ) -> &[String] {
+        &self.missing_out_dirs
+    }
+
+    /// How long loading the workspace took, as the `load` phase
+    pub fn load_timings( &self ) -> &Timings {
+        &self.load_timings
+    }
+
+    /// The number of crates in the crate graph, and in the whole workspace.
+    /// These differ if the session was scoped to a single package.
+    pub fn crates( &self ) -> (usize, usize) {
+        self.crates
+    }
+
+    /// The directory containing the `Cargo.toml` the session was loaded from,
+    /// or the file itself for a detached file
+    pub fn manifest_dir( &self ) -> &PathBuf {
+        &self.manifest_dir
+    }
+
+    /// The root of the cargo workspace the session was loaded from. This is
+    /// `manifest_dir` unless the package is a member of a larger workspace.
+    pub fn workspace_root( &self ) -> &PathBuf {
+        &self.workspace_root
+    }
+
+    /// Whether `path` is a file of the workspace, or of the package if the
+    /// session is scoped to one
+    pub fn owns( &self, path: &AbsPathBuf ) -> bool {
+        let root: &PathBuf = if self.scoped { &self.manifest_dir } else { &self.workspace_root };
+        Path::new( path.as_str() ).starts_with( root ) && self.file_id( path ).is_ok()
+    }
+
+    /// Looks up the `FileId` of a file in the session's `Vfs`
+    pub fn file_id( &self, path: &AbsPathBuf ) -> Result<FileId, ExtractionError> {
+        get_file_id( path, &self.vfs )
+    }
+
+    /// Replaces the contents of a file in the database. Used to keep the
+    /// session in sync with the edits it has produced.
+    pub fn set_file_text( &mut self, file_id: FileId, text: String ) {
+        let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+        change.change_file( file_id, Some( text ) );
+        self.host.apply_change( change );
+    }
+
+    /// Applies the in-memory contents of an input to the database as a single
+    /// change. The contents stay in the session until they are replaced.
+    /// Returns the text each file had before, so it can be put back.
+    pub fn apply_contents( &mut self, input: &ExtractionInput ) -> Result<Vec<(FileId, Arc<str>)>, ExtractionError> {
+        let files: Vec<(&String, &String)> = input.overlays
+            .iter()
+            .chain( input.contents.as_ref().map( |text| ( &input.file_path, text ) ) )
+            .collect();
+        if files.is_empty() {
+            return Ok( vec![] );
+        }
+
+        let analysis: Analysis = run_analysis( &self.host );
+        let mut previous: Vec<(FileId, Arc<str>)> = Vec::new();
+        let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+        for (path, text) in files {
+            let abs_path: AbsPathBuf = convert_to_abs_path_buf( path )
+                .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+            let file_id: FileId = self.file_id( &abs_path )?;
+            previous.push( ( file_id, analysis.file_text( file_id )? ) );
+            change.change_file( file_id, Some( text.clone() ) );
+        }
+        drop( analysis );
+        self.host.apply_change( change );
+        Ok( previous )
+    }
+
+    /// Extracts the selected code into a new function.
+    /// If successful, returns an `ExtractionOutput` describing the new code.
+    /// The output code is applied back into the session, so a subsequent call
+    /// sees the updated file.
+    pub fn extract( &mut self, input: &ExtractionInput ) -> Result<ExtractionOutput, ExtractionError> {
+        let mut timings: Timings = Timings::default();
+        let mut timer: Instant = Instant::now();
+
+        // Unsaved contents replace what was loaded from disk before anything
+        // is analysed, so the assist and its edit see the same text
+        let previous: Vec<(FileId, Arc<str>)> = self.apply_contents( input )?;
+        let prepared: Prepared = match self.prepare( input, &mut timings, &mut timer ) {
+            Ok( prepared ) => prepared,
+            Err( e ) => {
+                self.restore( &previous );
+                return Err( e );
+            },
+        };
+
+        // If the assist can't be applied, the session is left as it was
+        let output: ExtractionOutput = match self.apply( input, &prepared, &mut timings, &mut timer ) {
+            Ok( output ) => output,
+            Err( e ) => {
+                self.restore( &prepared.replaced );
+                self.restore( &previous );
+                return Err( e );
+            },
+        };
+
+        self.set_file_text( prepared.file_id, output.code.clone() );
+
+        Ok( output )
+    }
+
+    /// Applies the assist found by `prepare`, and describes the result.
+    /// The analysis is a snapshot of the database, so it is dropped before
+    /// the edit can be applied back into the host.
+    fn apply(
+        &self,
+        input: &ExtractionInput,
+        prepared: &Prepared,
+        timings: &mut Timings,
+        timer: &mut Instant,
+    ) -> Result<ExtractionOutput, ExtractionError> {
+        let analysis: Analysis = run_analysis( &self.host );
+        let (modified_code, edits): (String, Vec<TextEdit>) = apply_extract_function(
+            &prepared.assist,
+            &prepared.path,
+            &self.vfs,
+            &analysis,
+            &prepared.callee_name,
+            prepared.edition,
+            prepared.no_std,
+        )?;
+        timings.lap( "apply", timer );
+
+        let mut output: ExtractionOutput = build_output(
+            &prepared.source_file,
+            prepared.range,
+            modified_code,
+            edits,
+            prepared.edition,
+            &input.file_path,
+            prepared.source_hash,
+        )?;
+        timings.lap( "output", timer );
+        output.relative_path = relative_path( &input.file_path, &self.workspace_root );
+        output.timings = timings.clone();
+        output.missing_out_dirs = self.missing_out_dirs.clone();
+        Ok( output )
+    }
+
+    /// Checks whether the selection can be extracted, without changing the
+    /// session. Returns the range that would be extracted.
+    pub fn check( &mut self, input: &ExtractionInput ) -> Result<TextRange, ExtractionError> {
+        let mut timings: Timings = Timings::default();
+        let mut timer: Instant = Instant::now();
+
+        // The contents and overlays are only used for the check, as are the
+        // files without their markers. `prepare` has already put the markers
+        // back if it failed.
+        let previous: Vec<(FileId, Arc<str>)> = self.apply_contents( input )?;
+        let prepared: Result<Prepared, ExtractionError> = self.prepare( input, &mut timings, &mut timer );
+        if let Ok( prepared ) = &prepared {
+            self.restore( &prepared.replaced );
+        }
+        self.restore( &previous );
+        let (start, end): (u32, u32) = prepared?.range;
+        Ok( TextRange::new( start.into(), end.into() ) )
+    }
+
+    /// Puts back the text of files that an extraction replaced
+    fn restore( &mut self, replaced: &[(FileId, Arc<str>)] ) {
+        if replaced.is_empty() {
+            return;
+        }
+        let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+        for (file_id, text) in replaced {
+            change.change_file( *file_id, Some( text.to_string() ) );
+        }
+        self.host.apply_change( change );
+    }
+
+    /// Runs everything up to applying the assist: resolves the selection,
+    /// checks it and the new name, and finds the extract function assist.
+    /// If it fails, any text the selection replaced (the file without its
+    /// markers) is put back.
+    fn prepare(
+        &mut self,
+        input: &ExtractionInput,
+        timings: &mut Timings,
+        timer: &mut Instant,
+    ) -> Result<Prepared, ExtractionError> {
+        let mut replaced: Vec<(FileId, Arc<str>)> = Vec::new();
+        let result: Result<Prepared, ExtractionError> = self.prepare_inner( input, &mut replaced, timings, timer );
+        match result {
+            Ok( prepared ) => Ok( Prepared { replaced, ..prepared } ),
+            Err( e ) => {
+                self.restore( &replaced );
+                Err( e )
+            },
+        }
+    }
+
+    fn prepare_inner(
+        &mut self,
+        input: &ExtractionInput,
+        replaced: &mut Vec<(FileId, Arc<str>)>,
+        timings: &mut Timings,
+        timer: &mut Instant,
+    ) -> Result<Prepared, ExtractionError> {
+        let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( &input.file_path )
+            .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+
+        // Verify the input data
+        verify_input( input )?;
+
+        // Remember the text the extraction starts from, so the output isn't
+        // written over a file that has changed since. In-memory contents are
+        // used as they are, and the file doesn't need to be on disk.
+        let source_hash: u64 = match &input.contents {
+            Some( contents ) => content_hash( contents.as_bytes() ),
+            None => content_hash( &fs::read( &input.file_path )? ),
+        };
+
+        // Extract the struct information. A selection is resolved against the
+        // text the session has for the file, which may differ from the disk.
+        // If markers were removed, the stripped text replaces the file.
+        let (range_, marker_name): ((u32, u32), Option<String>) = match &input.selection {
+            Some( selection ) => {
+                let file_id: FileId = self.file_id( &input_abs_path )?;
+                let text: Arc<str> = run_analysis( &self.host ).file_text( file_id )?;
+                let resolved: ResolvedSelection = {
+                    let sema: Semantics<'_, RootDatabase> = Semantics::new( self.host.raw_database() );
+                    selection.resolve_in( &sema, file_id, &text )?
+                };
+                if let Some( stripped ) = resolved.text {
+                    replaced.push( ( file_id, text ) );
+                    self.set_file_text( file_id, stripped );
+                }
+                ( resolved.range, resolved.name )
+            },
+            None => ( ( input.start_idx, input.end_idx ), None ),
+        };
+        check_idx( &range_ )?;
+
+        // A name on the start marker is used if no name was given
+        let new_fn_name: &str = match &marker_name {
+            Some( name ) if input.new_fn_name.is_empty() => name,
+            _ => &input.new_fn_name,
+        };
+        timings.lap( "resolve", timer );
+
+        let db: &RootDatabase = self.host.raw_database();
+
+        // Before we go too far, lets do few more quick checks now that we
+        // have the analysis
+        // 1. Check if the function to extract is not just a comment
+        // 2. Check if the function to extract has matching braces
+        // 3. Convert the range to a trimmed range.
+        let sema: Semantics<'_, RootDatabase> = Semantics::new( db );
+        let frange_: ra_ap_hir::FileRangeWrapper<FileId> = generate_frange( &input_abs_path, &self.vfs, range_ )?;
+        // Parse with the crate's own edition, so the checks see the same tree
+        // as the assist
+        let editioned_file_id: EditionedFileId = EditionedFileId::new(
+            frange_.file_id,
+            file_edition( &sema, frange_.file_id ),
+        );
+        let source_file: SourceFile = sema.parse( editioned_file_id );
+        let range: (u32, u32) = trim_range( &source_file, &range_ );
+        timings.lap( "parse", timer );
+
+        let analysis: Analysis = run_analysis( &self.host );
+
+        // Move the selection onto the nearest extractable range if asked to
+        let range: (u32, u32) = if input.snap {
+            snap_range( &analysis, &self.vfs, &input_abs_path, &source_file, range )?
+        } else {
+            range
+        };
+        check_comment( &source_file, &range )?;
+        check_braces( &source_file, &range )?;
+
+        // Make sure the new name is usable where the function will go
+        let callee_name: String = check_fn_name( new_fn_name, editioned_file_id.edition(), input.allow_name_fallback )?;
+        let callee_name: String = check_name_in_scope(
+            &sema,
+            &self.vfs,
+            &source_file,
+            range,
+            &callee_name,
+            input.allow_name_fallback,
+        )?;
+        timings.lap( "checks", timer );
+
+        let assists: Vec<Assist> = get_assists( &analysis, &self.vfs, &input_abs_path, range )?;
+        let assist: Assist = filter_extract_function_assist( assists )
+            .ok_or_else( || ExtractionError::NoExtractFunction(
+                diagnose_selection( &source_file, range )
+            ) )?;
+        timings.lap( "assists", timer );
+
+        Ok( Prepared {
+            path: input_abs_path,
+            file_id: frange_.file_id,
+            edition: editioned_file_id.edition(),
+            no_std: crate_is_no_std( &sema, frange_.file_id ),
+            source_file,
+            range,
+            callee_name,
+            assist,
+            source_hash,
+            replaced: Vec::new(),
+        } )
+    }
+}
+
+/// The directory that identifies the session for the package in
+/// `manifest_dir`: the root of its cargo workspace, so that all members share
+/// one session, or `manifest_dir` itself if sessions are scoped to a single
+/// package
+pub fn session_root( manifest_dir: &PathBuf, options: &LoadOptions ) -> Result<PathBuf, ExtractionError> {
+    if options.scoped {
+        Ok( manifest_dir.clone() )
+    } else {
+        get_workspace_root( manifest_dir )
+    }
+}
+
+/// The sessions of every workspace a long running process has used, by
+/// `session_root`
+#[derive(Default)]
+pub struct SessionCache {
+    sessions: HashMap<PathBuf, ExtractionSession>,
+    options: LoadOptions,
+}
+
+impl SessionCache {
+    pub fn new() -> Self {
+        SessionCache::default()
+    }
+
+    /// A cache that loads every workspace with the given options
+    pub fn with_options( options: LoadOptions ) -> Self {
+        SessionCache {
+            sessions: HashMap::new(),
+            options,
+        }
+    }
+
+    /// The workspace whose session `file` belongs to. A loaded session that
+    /// already has the file is used, so the members of a workspace share one
+    /// session. Otherwise this is the `session_root` of the file's package.
+    pub fn workspace_of( &self, file: &str ) -> Result<PathBuf, ExtractionError> {
+        let abs_path: AbsPathBuf = convert_to_abs_path_buf( file )
+            .map_err( |path| ExtractionError::InvalidPath( path.to_string() ) )?;
+        let owner: Option<&PathBuf> = self.sessions
+            .iter()
+            .find( |(_, session)| session.owns( &abs_path ) )
+            .map( |(workspace, _)| workspace );
+        match owner {
+            Some( workspace ) => Ok( workspace.clone() ),
+            None => session_root( &get_manifest_dir( &PathBuf::from( abs_path.as_str() ) )?, &self.options ),
+        }
+    }
+
+    pub fn contains( &self, workspace: &Path ) -> bool {
+        self.sessions.contains_key( workspace )
+    }
+
+    /// Finds the session for the workspace containing `file`, loading it if
+    /// it isn't loaded yet
+    pub fn get_or_load( &mut self, file: &str ) -> Result<(PathBuf, &mut ExtractionSession), ExtractionError> {
+        let workspace: PathBuf = self.workspace_of( file )?;
+        if !self.sessions.contains_key( &workspace ) {
+            let session: ExtractionSession = ExtractionSession::load_with_options( file, &self.options )?;
+            self.sessions.insert( workspace.clone(), session );
+        }
+        let session: &mut ExtractionSession = self.sessions
+            .get_mut( &workspace )
+            .expect( "the session was just inserted" );
+        Ok( ( workspace, session ) )
+    }
+
+    /// Drops the session for a workspace. Returns whether it was loaded.
+    pub fn remove( &mut self, workspace: &Path ) -> bool {
+        self.sessions.remove( workspace ).is_some()
+    }
+}
+
+/// Everything `extract` needs from `prepare` to apply the assist
+struct Prepared {
+    path: AbsPathBuf,
+    file_id: FileId,
+    edition: Edition,
+    no_std: bool,
+    source_file: SourceFile,
+    range: (u32, u32),
+    callee_name: String,
+    assist: Assist,
+    source_hash: u64,
+    /// The text of files the extraction replaced, as it was before
+    replaced: Vec<(FileId, Arc<str>)>,
+}
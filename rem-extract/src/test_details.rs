@@ -277,6 +277,16 @@ lazy_static! {
             21,
             31,
         ),
+        TestFile::new(
+            "marker_comments",
+            0,
+            0,
+        ),
+        TestFile::new(
+            "marker_named_regions",
+            0,
+            0,
+        ),
         TestFile::new(
             "method_to_freestanding",
             59,
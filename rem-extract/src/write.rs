@@ -0,0 +1,130 @@
+//! Writing an `ExtractionOutput` back over the file it came from.
+//!
+//! The new contents are written to a temporary file in the same directory and
+//! then renamed over the original, so the file is never left half written.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::OsString,
+    fs::{
+        self,
+        Permissions,
+    },
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use tempfile::NamedTempFile;
+
+use crate::{
+    error::ExtractionError,
+    extraction::ExtractionOutput,
+};
+
+/// Hashes the contents of a file
+pub fn content_hash( contents: &[u8] ) -> u64 {
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    contents.hash( &mut hasher );
+    hasher.finish()
+}
+
+/// The path of the backup kept by `write_in_place`, e.g. `main.rs.orig`
+pub fn backup_path( path: &Path ) -> PathBuf {
+    let mut file_name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push( ".orig" );
+    path.with_file_name( file_name )
+}
+
+/// Atomically replaces the file the output was extracted from with the output
+/// code, keeping its permissions. If `backup` is set, the original is copied to
+/// `<file>.orig` first.
+/// Returns `ExtractionError::FileChangedOnDisk` (and writes nothing) if the file
+/// has changed since the extraction ran.
+pub fn write_in_place( output: &ExtractionOutput, backup: bool ) -> Result<(), ExtractionError> {
+    let path: &Path = Path::new( &output.file_path );
+
+    let current: Vec<u8> = fs::read( path )?;
+    if content_hash( &current ) != output.source_hash {
+        return Err( ExtractionError::FileChangedOnDisk( output.file_path.clone() ) );
+    }
+    let permissions: Permissions = fs::metadata( path )?.permissions();
+
+    if backup {
+        fs::write( backup_path( path ), &current )?;
+    }
+
+    // The temporary file has to be in the same directory for the rename to be
+    // atomic
+    let dir: &Path = match path.parent() {
+        Some( dir ) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new( "." ),
+    };
+    let mut temp: NamedTempFile = NamedTempFile::new_in( dir )?;
+    temp.write_all( output.code.as_bytes() )?;
+    temp.as_file().sync_all()?;
+    temp.as_file().set_permissions( permissions )?;
+    temp.persist( path ).map_err( |e| ExtractionError::Io( e.error ) )?;
+
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::FunctionInfo;
+    use ra_ap_ide::TextRange;
+
+    fn output_for( path: &Path, original: &str, code: &str ) -> ExtractionOutput {
+        let function: FunctionInfo = FunctionInfo {
+            name: String::new(),
+            text: String::new(),
+            range: TextRange::default(),
+        };
+        ExtractionOutput {
+            code: code.to_string(),
+            original_code: original.to_string(),
+            callee: function.clone(),
+            caller: function,
+            params: vec![],
+            ret_type: None,
+            edits: vec![],
+            range: TextRange::default(),
+            file_path: path.to_string_lossy().to_string(),
+            relative_path: path.to_string_lossy().to_string(),
+            source_hash: content_hash( original.as_bytes() ),
+            timings: Default::default(),
+            missing_out_dirs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_write_in_place_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join( "main.rs" );
+        fs::write( &path, "fn main() {}\n" ).unwrap();
+
+        let output: ExtractionOutput = output_for( &path, "fn main() {}\n", "fn main() { fun_name() }\n" );
+        write_in_place( &output, true ).unwrap();
+
+        assert_eq!( fs::read_to_string( &path ).unwrap(), "fn main() { fun_name() }\n" );
+        assert_eq!( fs::read_to_string( backup_path( &path ) ).unwrap(), "fn main() {}\n" );
+    }
+
+    #[test]
+    fn test_write_in_place_refuses_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join( "main.rs" );
+        fs::write( &path, "fn main() { changed() }\n" ).unwrap();
+
+        let output: ExtractionOutput = output_for( &path, "fn main() {}\n", "fn main() { fun_name() }\n" );
+        assert!( matches!( write_in_place( &output, false ), Err( ExtractionError::FileChangedOnDisk( _ ) ) ) );
+        assert_eq!( fs::read_to_string( &path ).unwrap(), "fn main() { changed() }\n" );
+    }
+}
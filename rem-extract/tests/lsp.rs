@@ -0,0 +1,223 @@
+//! Drives `rem-extract lsp` through its stdin and stdout, the way an editor
+//! would.
+
+use std::{
+    fs,
+    io::{
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    },
+    path::Path,
+    process::{
+        Child,
+        ChildStdin,
+        ChildStdout,
+        Command,
+        Stdio,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+/// A minimal LSP client for the server
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+impl Client {
+    /// Starts the server, and runs the `initialize` handshake
+    fn spawn() -> Self {
+        let mut child: Child = Command::new( env!( "CARGO_BIN_EXE_rem-extract" ) )
+            .arg( "lsp" )
+            .stdin( Stdio::piped() )
+            .stdout( Stdio::piped() )
+            .spawn()
+            .expect( "failed to start the server" );
+        let stdin: ChildStdin = child.stdin.take().unwrap();
+        let stdout: BufReader<ChildStdout> = BufReader::new( child.stdout.take().unwrap() );
+        let mut client: Client = Client { child, stdin, stdout, next_id: 1 };
+
+        let response: Value = client.request( "initialize", json!( { "processId": null, "rootUri": null, "capabilities": {} } ) );
+        assert!( response["result"]["capabilities"]["codeActionProvider"].is_object(), "{}", response );
+        client.notify( "initialized", json!( {} ) );
+        client
+    }
+
+    fn send( &mut self, message: Value ) {
+        let body: String = message.to_string();
+        write!( self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body ).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    /// Reads the next message from the server
+    fn receive( &mut self ) -> Value {
+        let mut length: usize = 0;
+        loop {
+            let mut header: String = String::new();
+            self.stdout.read_line( &mut header ).unwrap();
+            let header: &str = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some( value ) = header.strip_prefix( "Content-Length: " ) {
+                length = value.parse().unwrap();
+            }
+        }
+        let mut body: Vec<u8> = vec![ 0; length ];
+        self.stdout.read_exact( &mut body ).unwrap();
+        serde_json::from_slice( &body ).expect( "the message is not JSON" )
+    }
+
+    fn notify( &mut self, method: &str, params: Value ) {
+        self.send( json!( { "jsonrpc": "2.0", "method": method, "params": params } ) );
+    }
+
+    /// Sends a request, and returns the messages the server sent up to and
+    /// including the response to it
+    fn request_all( &mut self, method: &str, params: Value ) -> Vec<Value> {
+        let id: u64 = self.next_id;
+        self.next_id += 1;
+        self.send( json!( { "jsonrpc": "2.0", "id": id, "method": method, "params": params } ) );
+        let mut messages: Vec<Value> = vec![];
+        loop {
+            let message: Value = self.receive();
+            let done: bool = message["id"] == id && message.get( "method" ).is_none();
+            messages.push( message );
+            if done {
+                return messages;
+            }
+        }
+    }
+
+    fn request( &mut self, method: &str, params: Value ) -> Value {
+        self.request_all( method, params ).pop().unwrap()
+    }
+
+    fn shutdown( mut self ) {
+        let response: Value = self.request( "shutdown", Value::Null );
+        assert!( response["result"].is_null(), "{}", response );
+        self.notify( "exit", Value::Null );
+        assert!( self.child.wait().unwrap().success() );
+    }
+}
+
+/// Creates a single crate workspace containing `src/main.rs`, and returns the
+/// `file://` URI of `main.rs`
+fn create_project( dir: &Path, main_rs: &str ) -> String {
+    fs::create_dir_all( dir.join( "src" ) ).unwrap();
+    fs::write(
+        dir.join( "Cargo.toml" ),
+        "[package]\nname = \"lsp_test\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    ).unwrap();
+    fs::write( dir.join( "src/main.rs" ), main_rs ).unwrap();
+    format!( "file://{}", dir.join( "src/main.rs" ).to_string_lossy() )
+}
+
+/// The LSP position (0-based, in UTF-16 code units) of a byte offset
+fn position( text: &str, offset: usize ) -> Value {
+    let line: usize = text[..offset].matches( '\n' ).count();
+    let line_start: usize = text[..offset].rfind( '\n' ).map_or( 0, |idx| idx + 1 );
+    json!( { "line": line, "character": text[line_start..offset].encode_utf16().count() } )
+}
+
+fn range_of( text: &str, needle: &str ) -> Value {
+    let start: usize = text.find( needle ).unwrap();
+    json!( { "start": position( text, start ), "end": position( text, start + needle.len() ) } )
+}
+
+/// Applies LSP text edits, which are all relative to `text`
+fn apply_edits( text: &str, edits: &[Value] ) -> String {
+    let offset = |position: &Value| -> usize {
+        let line: usize = position["line"].as_u64().unwrap() as usize;
+        let line_start: usize = text.split_inclusive( '\n' ).take( line ).map( str::len ).sum();
+        let units: usize = position["character"].as_u64().unwrap() as usize;
+        let mut width: usize = 0;
+        let chars: usize = text[line_start..]
+            .chars()
+            .take_while( |c| {
+                width += c.len_utf16();
+                width <= units
+            } )
+            .map( char::len_utf8 )
+            .sum();
+        line_start + chars
+    };
+    let mut ranges: Vec<(usize, usize, &str)> = edits
+        .iter()
+        .map( |edit| (
+            offset( &edit["range"]["start"] ),
+            offset( &edit["range"]["end"] ),
+            edit["newText"].as_str().unwrap(),
+        ) )
+        .collect();
+    ranges.sort_by_key( |(start, _, _)| std::cmp::Reverse( *start ) );
+    let mut text: String = text.to_string();
+    for (start, end, new_text) in ranges {
+        text.replace_range( start..end, new_text );
+    }
+    text
+}
+
+#[test]
+fn test_code_action_and_execute_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let uri: String = create_project( dir.path(), "fn main() {}\n" );
+    // The open document differs from the file on disk. It has non-ASCII text
+    // before and in the selection, so UTF-16 and byte offsets differ.
+    let text: &str = "fn main() {\n    let s = \"🦀\"; let é = 1 + 2;\n    let b = é * 3;\n    println!(\"{} {}\", s, b);\n}\n";
+
+    let mut client: Client = Client::spawn();
+    client.notify( "textDocument/didOpen", json!( {
+        "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": text },
+    } ) );
+
+    // Nothing is offered for a selection that can't be extracted
+    let response: Value = client.request( "textDocument/codeAction", json!( {
+        "textDocument": { "uri": uri },
+        "range": range_of( text, "fn main" ),
+        "context": { "diagnostics": [] },
+    } ) );
+    assert_eq!( response["result"], json!( [] ), "{}", response );
+
+    let response: Value = client.request( "textDocument/codeAction", json!( {
+        "textDocument": { "uri": uri },
+        "range": range_of( text, "é * 3" ),
+        "context": { "diagnostics": [] },
+    } ) );
+    let action: &Value = &response["result"][0];
+    assert_eq!( action["kind"], "refactor.extract", "{}", response );
+    assert_eq!( action["command"]["command"], "rem.extractMethod", "{}", response );
+
+    let mut argument: Value = action["command"]["arguments"][0].clone();
+    argument["name"] = json!( "triple" );
+    let messages: Vec<Value> = client.request_all( "workspace/executeCommand", json!( {
+        "command": "rem.extractMethod",
+        "arguments": [ argument ],
+    } ) );
+    let response: &Value = messages.last().unwrap();
+    assert!( response["result"].is_null(), "{}", response );
+
+    let apply: &Value = messages
+        .iter()
+        .find( |message| message["method"] == "workspace/applyEdit" )
+        .unwrap_or_else( || panic!( "no workspace/applyEdit in {:?}", messages ) );
+    let edits: &Vec<Value> = apply["params"]["edit"]["changes"][&uri].as_array().unwrap();
+    let edited: String = apply_edits( text, edits );
+    assert!( edited.contains( "    let b = triple(é);\n" ), "{}", edited );
+    assert!( edited.contains( "fn triple(é: i32) -> i32 {\n    é * 3\n}" ), "{}", edited );
+    assert!( edited.contains( "let s = \"🦀\"; let é = 1 + 2;" ), "{}", edited );
+    assert!( !edited.contains( "fun_name" ), "{}", edited );
+
+    // The file on disk is left to the client
+    assert_eq!( fs::read_to_string( dir.path().join( "src/main.rs" ) ).unwrap(), "fn main() {}\n" );
+
+    client.shutdown();
+}
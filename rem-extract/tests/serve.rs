@@ -0,0 +1,218 @@
+//! Drives `rem-extract serve` through its stdin and stdout, the way an editor
+//! or `rem-cli` would.
+
+use std::{
+    fs,
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Child,
+        ChildStdin,
+        ChildStdout,
+        Command,
+        Stdio,
+    },
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+/// A minimal JSON-RPC client for the server
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+impl Client {
+    fn spawn() -> Self {
+        Client::spawn_with( &[] )
+    }
+
+    /// Starts the server with extra arguments for `serve`
+    fn spawn_with( args: &[&str] ) -> Self {
+        let mut child: Child = Command::new( env!( "CARGO_BIN_EXE_rem-extract" ) )
+            .arg( "serve" )
+            .args( args )
+            .stdin( Stdio::piped() )
+            .stdout( Stdio::piped() )
+            .spawn()
+            .expect( "failed to start the server" );
+        let stdin: ChildStdin = child.stdin.take().unwrap();
+        let stdout: BufReader<ChildStdout> = BufReader::new( child.stdout.take().unwrap() );
+        Client { child, stdin, stdout, next_id: 1 }
+    }
+
+    /// Sends a raw line, and reads the response to it
+    fn send_raw( &mut self, line: &str ) -> Value {
+        writeln!( self.stdin, "{}", line ).unwrap();
+        self.stdin.flush().unwrap();
+        let mut response: String = String::new();
+        self.stdout.read_line( &mut response ).unwrap();
+        serde_json::from_str( &response ).expect( "the response is not JSON" )
+    }
+
+    fn request( &mut self, method: &str, params: Value ) -> Value {
+        let id: u64 = self.next_id;
+        self.next_id += 1;
+        let request: Value = json!( { "jsonrpc": "2.0", "id": id, "method": method, "params": params } );
+        let response: Value = self.send_raw( &request.to_string() );
+        assert_eq!( response["id"], id );
+        response
+    }
+
+    fn shutdown( mut self ) {
+        let response: Value = self.request( "shutdown", Value::Null );
+        assert!( response["result"].is_null() );
+        assert!( self.child.wait().unwrap().success() );
+    }
+}
+
+/// Creates a single crate workspace containing `src/main.rs`
+fn create_project( dir: &Path, main_rs: &str ) {
+    fs::create_dir_all( dir.join( "src" ) ).unwrap();
+    fs::write(
+        dir.join( "Cargo.toml" ),
+        "[package]\nname = \"serve_test\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    ).unwrap();
+    fs::write( dir.join( "src/main.rs" ), main_rs ).unwrap();
+}
+
+/// Creates a workspace with a `lib.rs` member for each name
+fn create_workspace( dir: &Path, members: &[&str], lib_rs: &str ) {
+    let quoted: Vec<String> = members.iter().map( |member| format!( "\"{}\"", member ) ).collect();
+    fs::write(
+        dir.join( "Cargo.toml" ),
+        format!( "[workspace]\nmembers = [{}]\nresolver = \"2\"\n", quoted.join( ", " ) ),
+    ).unwrap();
+    for member in members {
+        let member_dir: PathBuf = dir.join( member );
+        fs::create_dir_all( member_dir.join( "src" ) ).unwrap();
+        fs::write(
+            member_dir.join( "Cargo.toml" ),
+            format!( "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", member ),
+        ).unwrap();
+        fs::write( member_dir.join( "src/lib.rs" ), lib_rs ).unwrap();
+    }
+}
+
+#[test]
+fn test_protocol_errors() {
+    let mut client: Client = Client::spawn();
+
+    let response: Value = client.send_raw( "{ not json" );
+    assert_eq!( response["error"]["code"], -32700 );
+
+    let response: Value = client.send_raw( r#"{"id": 1, "method": "check"}"# );
+    assert_eq!( response["error"]["code"], -32600 );
+
+    let response: Value = client.request( "no/such/method", json!( {} ) );
+    assert_eq!( response["error"]["code"], -32601 );
+
+    let response: Value = client.request( "extract", json!( { "file": "src/main.rs" } ) );
+    assert_eq!( response["error"]["code"], -32602 );
+    assert_eq!( response["error"]["data"]["kind"], "InvalidRequest" );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_check_update_and_extract() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn main() {\n    let a = 1;\n    println!(\"{}\", a);\n}\n";
+    create_project( dir.path(), main_rs );
+    let file: String = dir.path().join( "src/main.rs" ).to_string_lossy().to_string();
+    let start: usize = main_rs.find( "1;" ).unwrap();
+
+    let mut client: Client = Client::spawn();
+
+    let response: Value = client.request( "workspace/open", json!( { "file": file } ) );
+    assert!( response["result"]["workspace"].is_string(), "{}", response );
+
+    let response: Value = client.request( "check", json!( { "file": file, "name": "one", "start": start, "end": start + 1 } ) );
+    assert_eq!( response["result"]["applicable"], true, "{}", response );
+
+    // The `fn` keyword on its own is not an expression
+    let response: Value = client.request( "check", json!( { "file": file, "name": "one", "start": 0, "end": 2 } ) );
+    assert_eq!( response["result"]["applicable"], false, "{}", response );
+
+    // Unsaved contents are used instead of the file on disk
+    let unsaved: String = main_rs.replace( "let a = 1;", "let a = 2;" );
+    let response: Value = client.request( "file/update", json!( { "file": file, "text": unsaved } ) );
+    assert!( response.get( "error" ).is_none(), "{}", response );
+
+    let response: Value = client.request( "extract", json!( { "file": file, "name": "two", "start": start, "end": start + 1 } ) );
+    let code: &str = response["result"]["code"].as_str().unwrap_or_else( || panic!( "{}", response ) );
+    assert!( code.contains( "let a = two();" ), "{}", code );
+    assert!( code.contains( "fn two() -> i32" ), "{}", code );
+
+    // Nothing is written to disk
+    assert_eq!( fs::read_to_string( &file ).unwrap(), main_rs );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_workspace_members_share_a_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_rs: &str = "pub fn f() -> i32 {\n    let a = 1;\n    a\n}\n";
+    create_workspace( dir.path(), &[ "first", "second" ], lib_rs );
+    let first: String = dir.path().join( "first/src/lib.rs" ).to_string_lossy().to_string();
+    let second: String = dir.path().join( "second/src/lib.rs" ).to_string_lossy().to_string();
+    let start: usize = lib_rs.find( "1;" ).unwrap();
+
+    let mut client: Client = Client::spawn();
+
+    // Both members are in the session of the workspace root
+    let response: Value = client.request( "workspace/open", json!( { "file": first } ) );
+    let workspace: Value = response["result"]["workspace"].clone();
+    let root: &Path = Path::new( workspace.as_str().unwrap_or_else( || panic!( "{}", response ) ) );
+    assert_eq!( root.file_name(), dir.path().file_name(), "{}", response );
+    let response: Value = client.request( "workspace/open", json!( { "file": second } ) );
+    assert_eq!( response["result"]["workspace"], workspace, "{}", response );
+
+    let response: Value = client.request( "extract", json!( { "file": second, "name": "one", "start": start, "end": start + 1 } ) );
+    let code: &str = response["result"]["code"].as_str().unwrap_or_else( || panic!( "{}", response ) );
+    assert!( code.contains( "fn one() -> i32" ), "{}", code );
+
+    // So closing it once closes it for both
+    let response: Value = client.request( "workspace/close", json!( { "workspace": workspace } ) );
+    assert_eq!( response["result"]["closed"], true, "{}", response );
+    let response: Value = client.request( "workspace/close", json!( { "workspace": workspace } ) );
+    assert_eq!( response["result"]["closed"], false, "{}", response );
+
+    client.shutdown();
+}
+
+#[test]
+fn test_scoped_workspace() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib_rs: &str = "pub fn f() -> i32 {\n    let a = 1;\n    a\n}\n";
+    create_workspace( dir.path(), &[ "first", "second", "third" ], lib_rs );
+    let file: String = dir.path().join( "first/src/lib.rs" ).to_string_lossy().to_string();
+    let start: usize = lib_rs.find( "1;" ).unwrap();
+
+    let mut client: Client = Client::spawn_with( &[ "--scoped" ] );
+
+    // Only `first` is kept out of the three members
+    let response: Value = client.request( "workspace/open", json!( { "file": file } ) );
+    assert_eq!( response["result"]["crates"], 1, "{}", response );
+    assert_eq!( response["result"]["workspace_crates"], 3, "{}", response );
+    assert!( response["result"]["timings"]["load_ms"].is_number(), "{}", response );
+
+    let response: Value = client.request( "extract", json!( { "file": file, "name": "one", "start": start, "end": start + 1 } ) );
+    let code: &str = response["result"]["code"].as_str().unwrap_or_else( || panic!( "{}", response ) );
+    assert!( code.contains( "fn one() -> i32" ), "{}", code );
+
+    client.shutdown();
+}
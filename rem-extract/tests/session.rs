@@ -0,0 +1,281 @@
+//! Extractions through a long lived `ExtractionSession`.
+
+use std::{
+    fs,
+    path::Path,
+    process::{
+        Command,
+        Output,
+    },
+};
+
+use rem_extract::{
+    config::LoadOptions,
+    error::ExtractionError,
+    extraction::{
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    selection::Selection,
+    session::ExtractionSession,
+    write::content_hash,
+};
+
+use ra_ap_vfs::AbsPathBuf;
+
+/// Creates a single crate workspace containing `src/main.rs`, and returns the
+/// path of `main.rs`
+fn create_project( dir: &Path, main_rs: &str ) -> String {
+    fs::create_dir_all( dir.join( "src" ) ).unwrap();
+    fs::write(
+        dir.join( "Cargo.toml" ),
+        "[package]\nname = \"session_test\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    ).unwrap();
+    fs::write( dir.join( "src/main.rs" ), main_rs ).unwrap();
+    dir.join( "src/main.rs" ).to_string_lossy().to_string()
+}
+
+/// The byte range of the first `needle` in `text`
+fn range_of( text: &str, needle: &str ) -> (u32, u32) {
+    let start: usize = text.find( needle ).unwrap_or_else( || panic!( "`{}` not in {}", needle, text ) );
+    ( start as u32, ( start + needle.len() ) as u32 )
+}
+
+#[test]
+fn test_chained_extractions() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn main() {\n    let a = 1 + 2;\n    let b = a * 3;\n    println!(\"{}\", b);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+
+    let (start, end): (u32, u32) = range_of( main_rs, "1 + 2" );
+    let first: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "sum", start, end ) ).unwrap();
+    assert!( first.code.contains( "let a = sum();" ), "{}", first.code );
+
+    // The second selection is in the text produced by the first extraction,
+    // which is only in the session, not on disk
+    let (start, end): (u32, u32) = range_of( &first.code, "a * 3" );
+    let second: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "triple", start, end ) ).unwrap();
+    assert_eq!( second.original_code, first.code );
+    assert!( second.code.contains( "let a = sum();" ), "{}", second.code );
+    assert!( second.code.contains( "let b = triple(a);" ), "{}", second.code );
+    assert!( second.code.contains( "fn sum() -> i32" ), "{}", second.code );
+    assert!( second.code.contains( "fn triple(a: i32) -> i32" ), "{}", second.code );
+
+    assert_eq!( fs::read_to_string( &file ).unwrap(), main_rs );
+}
+
+#[test]
+fn test_existing_fun_name_is_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn fun_name() -> i32 {\n    2\n}\n\nfn main() {\n    let a = fun_name() * 3;\n    println!(\"{}\", a);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+
+    // rust-analyzer generates `fun_name1` here, which is then renamed
+    let (start, end): (u32, u32) = range_of( main_rs, "fun_name() * 3" );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "triple", start, end ) ).unwrap();
+    assert_eq!( output.callee.name, "triple" );
+    assert!( output.code.contains( "fn fun_name() -> i32 {\n    2\n}" ), "{}", output.code );
+    assert!( output.code.contains( "let a = triple();" ), "{}", output.code );
+    assert!( output.code.contains( "fn triple() -> i32 {\n    fun_name() * 3\n}" ), "{}", output.code );
+    assert!( !output.code.contains( "fun_name1" ), "{}", output.code );
+}
+
+#[test]
+fn test_generated_name_is_suffixed() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn fun_name() {}\n\nfn main() {\n    let a = 1 + 2;\n    fun_name();\n    println!(\"{}\", a);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+
+    // An empty name keeps the generated one, which avoids the existing name
+    let (start, end): (u32, u32) = range_of( main_rs, "1 + 2" );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "", start, end ) ).unwrap();
+    assert_eq!( output.callee.name, "fun_name1" );
+    assert!( output.code.contains( "let a = fun_name1();" ), "{}", output.code );
+    assert!( output.code.contains( "fn fun_name() {}" ), "{}", output.code );
+    assert!( output.code.contains( "    fun_name();\n" ), "{}", output.code );
+}
+
+#[test]
+fn test_name_shadowed_by_local() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn main() {\n    let triple = 3;\n    let a = 1 + 2;\n    println!(\"{} {}\", a, triple);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+
+    // A call to `triple()` would resolve to the local
+    let (start, end): (u32, u32) = range_of( main_rs, "1 + 2" );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let error: ExtractionError = session.extract( &ExtractionInput::new( &file, "triple", start, end ) ).unwrap_err();
+    assert!( matches!( error, ExtractionError::NameAlreadyDefined { .. } ), "{}", error );
+
+    // With a fallback, the next free name is used
+    let mut input: ExtractionInput = ExtractionInput::new( &file, "triple", start, end );
+    input.allow_name_fallback = true;
+    let output: ExtractionOutput = session.extract( &input ).unwrap();
+    assert!( output.code.contains( "let a = triple1();" ), "{}", output.code );
+}
+
+#[test]
+fn test_named_marker_regions() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "\
+fn main() {
+    // rem-extract:start first
+    let a = 1 + 2;
+    // rem-extract:end
+    // rem-extract:start second
+    let b = a * 3;
+    // rem-extract:end
+    println!(\"{}\", b);
+}
+";
+    let file: String = create_project( dir.path(), main_rs );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let markers = |name: &str, region: &str| {
+        let mut input: ExtractionInput = ExtractionInput::new( &file, name, 0, 0 );
+        input.selection = Some( Selection::Markers { name: Some( region.to_string() ) } );
+        input
+    };
+
+    // A failed request leaves the markers in the session
+    let error: ExtractionError = session.extract( &markers( "main", "second" ) ).unwrap_err();
+    assert!( matches!( error, ExtractionError::NameAlreadyDefined { .. } ), "{}", error );
+
+    let second: ExtractionOutput = session.extract( &markers( "triple", "second" ) ).unwrap();
+    assert!( second.code.contains( "// rem-extract:start first" ), "{}", second.code );
+    assert!( !second.code.contains( "// rem-extract:start second" ), "{}", second.code );
+
+    // The other region is still there for the next request
+    let first: ExtractionOutput = session.extract( &markers( "sum", "first" ) ).unwrap();
+    assert!( !first.code.contains( "rem-extract:" ), "{}", first.code );
+    assert!( first.code.contains( "let a = sum();" ), "{}", first.code );
+    assert!( first.code.contains( "let b = triple(a);" ), "{}", first.code );
+}
+
+#[test]
+fn test_diff_applies_with_git() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "fn main() {\n    let a = 1 + 2;\n    let b = a * 3;\n    println!(\"{}\", b);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+    let git = |args: &[&str]| -> Output {
+        Command::new( "git" ).args( args ).current_dir( dir.path() ).output().unwrap()
+    };
+    assert!( git( &[ "init", "-q" ] ).status.success() );
+
+    let (start, end): (u32, u32) = range_of( main_rs, "a * 3" );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "triple", start, end ) ).unwrap();
+    let diff: String = output.diff();
+    assert!( diff.starts_with( "--- a/src/main.rs\n+++ b/src/main.rs\n" ), "{}", diff );
+    fs::write( dir.path().join( "extract.patch" ), &diff ).unwrap();
+
+    let check: Output = git( &[ "apply", "--check", "extract.patch" ] );
+    assert!( check.status.success(), "{}\n{}", String::from_utf8_lossy( &check.stderr ), diff );
+    assert!( git( &[ "apply", "extract.patch" ] ).status.success() );
+    assert_eq!( fs::read_to_string( &file ).unwrap(), output.code );
+}
+
+#[test]
+fn test_check_leaves_the_session_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "mod util;\n\nfn main() {\n    let a = 1 + 2;\n    println!(\"{}\", util::double(a));\n}\n";
+    let util_rs: &str = "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+    let util: String = dir.path().join( "src/util.rs" ).to_string_lossy().to_string();
+    fs::write( &util, util_rs ).unwrap();
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+
+    let contents: &str = "mod util;\n\nfn main() {\n    let a = 10 + 20;\n    println!(\"{}\", util::double(a));\n}\n";
+    let (start, end): (u32, u32) = range_of( contents, "10 + 20" );
+    let mut input: ExtractionInput = ExtractionInput::new( &file, "sum", start, end );
+    input.contents = Some( contents.to_string() );
+    input.overlays.insert( util.clone(), "pub fn double(x: i32) -> i32 {\n    x + x\n}\n".to_string() );
+    session.check( &input ).unwrap();
+
+    // Neither the contents nor the overlay are left behind
+    let (start, end): (u32, u32) = range_of( main_rs, "1 + 2" );
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "sum", start, end ) ).unwrap();
+    assert_eq!( output.original_code, main_rs );
+    let (start, end): (u32, u32) = range_of( util_rs, "x * 2" );
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &util, "twice", start, end ) ).unwrap();
+    assert_eq!( output.original_code, util_rs );
+}
+
+#[test]
+fn test_contents_of_a_file_missing_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let main_rs: &str = "mod util;\n\nfn main() {\n    println!(\"{}\", util::double(3));\n}\n";
+    let util_rs: &str = "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+    let util: String = dir.path().join( "src/util.rs" ).to_string_lossy().to_string();
+    fs::write( &util, util_rs ).unwrap();
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+
+    // e.g. an editor buffer for a file deleted since the workspace was loaded
+    fs::remove_file( &util ).unwrap();
+    let (start, end): (u32, u32) = range_of( util_rs, "x * 2" );
+    let error: ExtractionError = session.extract( &ExtractionInput::new( &util, "twice", start, end ) ).unwrap_err();
+    assert!( matches!( error, ExtractionError::Io( _ ) ), "{}", error );
+
+    let mut input: ExtractionInput = ExtractionInput::new( &util, "twice", start, end );
+    input.contents = Some( util_rs.to_string() );
+    let output: ExtractionOutput = session.extract( &input ).unwrap();
+    assert!( output.code.contains( "fn twice(x: i32) -> i32" ), "{}", output.code );
+    // The hash is of the contents the extraction used
+    assert_eq!( output.source_hash, content_hash( util_rs.as_bytes() ) );
+}
+
+#[test]
+fn test_crate_edition_from_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    // Only parses as intended in 2015, where `async` is an identifier
+    let main_rs: &str = "fn main() {\n    let async = 2;\n    let b = async * 3;\n    println!(\"{}\", b);\n}\n";
+    let file: String = create_project( dir.path(), main_rs );
+    fs::write(
+        dir.path().join( "Cargo.toml" ),
+        "[package]\nname = \"session_test\"\nversion = \"0.1.0\"\nedition = \"2015\"\n\n[workspace]\n",
+    ).unwrap();
+
+    let (start, end): (u32, u32) = range_of( main_rs, "async * 3" );
+    let mut session: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    let output: ExtractionOutput = session.extract( &ExtractionInput::new( &file, "triple", start, end ) ).unwrap();
+    assert!( output.code.contains( "let b = triple(async);" ), "{}", output.code );
+    assert!( output.code.contains( "fn triple(async: i32) -> i32" ), "{}", output.code );
+}
+
+#[test]
+fn test_scoped_load_is_smaller() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write( dir.path().join( "Cargo.toml" ), "[workspace]\nmembers = [\"app\", \"util\", \"other\"]\nresolver = \"2\"\n" ).unwrap();
+    for (member, dependencies) in [ ( "app", "util = { path = \"../util\" }\n" ), ( "util", "" ), ( "other", "" ) ] {
+        fs::create_dir_all( dir.path().join( member ).join( "src" ) ).unwrap();
+        fs::write(
+            dir.path().join( member ).join( "Cargo.toml" ),
+            format!( "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n{}", member, dependencies ),
+        ).unwrap();
+        fs::write( dir.path().join( member ).join( "src/lib.rs" ), "pub fn f() -> i32 {\n    1 + 2\n}\n" ).unwrap();
+    }
+    let file: String = dir.path().join( "app/src/lib.rs" ).to_string_lossy().to_string();
+    let path_of = |member: &str| -> AbsPathBuf {
+        AbsPathBuf::try_from( dir.path().join( member ).join( "src/lib.rs" ).to_str().unwrap() ).unwrap()
+    };
+
+    let full: ExtractionSession = ExtractionSession::load( &file ).unwrap();
+    assert_eq!( full.crates(), ( 3, 3 ) );
+    assert!( full.file_id( &path_of( "other" ) ).is_ok() );
+
+    // `other` isn't a dependency of `app`, so neither its crate nor its files
+    // are loaded
+    let options: LoadOptions = LoadOptions { scoped: true, ..Default::default() };
+    let mut scoped: ExtractionSession = ExtractionSession::load_with_options( &file, &options ).unwrap();
+    assert_eq!( scoped.crates(), ( 2, 3 ) );
+    assert!( scoped.file_id( &path_of( "util" ) ).is_ok() );
+    assert!( scoped.file_id( &path_of( "other" ) ).is_err() );
+
+    let (start, end): (u32, u32) = range_of( "pub fn f() -> i32 {\n    1 + 2\n}\n", "1 + 2" );
+    let output: ExtractionOutput = scoped.extract( &ExtractionInput::new( &file, "three", start, end ) ).unwrap();
+    assert!( output.code.contains( "fn three() -> i32" ), "{}", output.code );
+}
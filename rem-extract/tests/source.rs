@@ -0,0 +1,97 @@
+//! Extraction from standalone source, without a Cargo project.
+
+use rem_extract::extraction::{
+    extract_from_source,
+    ExtractionOutput,
+    SOURCE_NAME,
+};
+
+use ra_ap_syntax::Edition;
+
+#[test]
+fn test_extract_from_source() {
+    let src: &str = "fn main() {\n    let a = 1;\n    println!(\"{}\", a);\n}\n";
+    let start: u32 = src.find( "1;" ).unwrap() as u32;
+
+    let output: ExtractionOutput = extract_from_source( src, ( start, start + 1 ), "one", Edition::Edition2021 ).unwrap();
+    assert!( output.code.contains( "let a = one();" ), "{}", output.code );
+    assert!( output.code.contains( "fn one() -> i32" ), "{}", output.code );
+    assert_eq!( output.original_code, src );
+    assert_eq!( output.file_path, SOURCE_NAME );
+}
+
+/// Extracts from `needle` to the end of its line, in a crate with the given
+/// edition. Every source below only parses as intended in its own edition.
+fn extract_tail( src: &str, needle: &str, edition: Edition ) -> ExtractionOutput {
+    let start: u32 = src.find( needle ).unwrap() as u32;
+    let end: u32 = start + src[start as usize..].find( '\n' ).unwrap() as u32;
+    extract_from_source( src, ( start, end ), "scale", edition )
+        .unwrap_or_else( |e| panic!( "{:?}: {}", edition, e ) )
+}
+
+#[test]
+fn test_edition_2015() {
+    // `async` is an identifier, and trait objects don't need `dyn`
+    let src: &str = "\
+trait Shape { fn area(&self) -> f64; }
+
+fn total(shapes: &[Box<Shape>]) -> f64 {
+    let async = 2.0;
+    let sum: f64 = shapes.iter().map(|s| s.area()).sum();
+    sum * async
+}
+";
+    let output: ExtractionOutput = extract_tail( src, "sum *", Edition::Edition2015 );
+    assert!( output.code.contains( "scale(sum, async)" ), "{}", output.code );
+    assert!( output.code.contains( "fn scale(sum: f64, async: f64) -> f64" ), "{}", output.code );
+}
+
+#[test]
+fn test_edition_2018() {
+    // Before 2018 `async fn` doesn't parse, and `.await` is a field
+    let src: &str = "\
+async fn area() -> f64 { 1.0 }
+
+async fn total() -> f64 {
+    let factor = 2.0;
+    area().await * factor
+}
+";
+    let output: ExtractionOutput = extract_tail( src, "area().await", Edition::Edition2018 );
+    assert!( output.code.contains( "scale(factor).await" ), "{}", output.code );
+    assert!( output.code.contains( "async fn scale(factor: f64) -> f64 {\n    area().await * factor\n}" ), "{}", output.code );
+}
+
+#[test]
+fn test_edition_2021() {
+    // `gen` is only reserved from 2024
+    let src: &str = "\
+trait Shape { fn area(&self) -> f64; }
+
+fn total(shapes: &[Box<dyn Shape>]) -> f64 {
+    let gen = 2.0;
+    let sum: f64 = shapes.iter().map(|s| s.area()).sum();
+    sum * gen
+}
+";
+    let output: ExtractionOutput = extract_tail( src, "sum *", Edition::Edition2021 );
+    assert!( output.code.contains( "scale(sum, gen)" ), "{}", output.code );
+    assert!( output.code.contains( "fn scale(sum: f64, gen: f64) -> f64" ), "{}", output.code );
+}
+
+#[test]
+fn test_edition_2024() {
+    // `gen` is a keyword, so the new parameter has to stay a raw identifier
+    let src: &str = "\
+trait Shape { fn area(&self) -> f64; }
+
+fn total(shapes: &[Box<dyn Shape>]) -> f64 {
+    let r#gen = 2.0;
+    let sum: f64 = shapes.iter().map(|s| s.area()).sum();
+    sum * r#gen
+}
+";
+    let output: ExtractionOutput = extract_tail( src, "sum *", Edition::Edition2024 );
+    assert!( output.code.contains( "scale(sum, r#gen)" ), "{}", output.code );
+    assert!( output.code.contains( "fn scale(sum: f64, r#gen: f64) -> f64" ), "{}", output.code );
+}